@@ -6,8 +6,9 @@ use arduino_due as board;
 use board::entry;
 use board::prelude::*;
 
-use board::hal::clock::SystemClocks;
+use board::hal::clock::{ClockConfig, MainClockSource};
 use board::hal::delay::Delay;
+use board::hal::time::MegaHertz;
 use board::hal::watchdog::WdtBuilder;
 use board::pac::{CorePeripherals, Peripherals};
 
@@ -15,16 +16,26 @@ use board::pac::{CorePeripherals, Peripherals};
 fn main() -> ! {
     let peripherals = Peripherals::take().unwrap();
     let core = CorePeripherals::take().unwrap();
-    let mut clocks = SystemClocks::new(peripherals.PMC, peripherals.SUPC);
+    let (mut sysclk, clocks) = ClockConfig::new(
+        peripherals.PMC,
+        peripherals.SUPC,
+        peripherals.EFC0,
+        peripherals.EFC1,
+    )
+    .main_clock(MainClockSource::MainXtal(board::MAIN_CRYSTAL))
+    .master_clock_frequency(MegaHertz(84))
+    .freeze()
+    .unwrap();
     let _ = WdtBuilder::from(peripherals.WDT).disable();
     let pins = board::Pins::new(
         peripherals.PIOA,
         peripherals.PIOB,
         peripherals.PIOC,
         peripherals.PIOD,
+        &mut sysclk,
     );
-    let mut led = pins.led_l.into_push_pull_output();
-    let mut delay = Delay::new(core.SYST, clocks.get_syscore());
+    let mut led: board::LedL<_> = pins.d13_tiob0.into_push_pull_output();
+    let mut delay = Delay::new(core.SYST, &clocks);
 
     loop {
         delay.try_delay_ms(200u8).unwrap();
@@ -6,12 +6,12 @@ use arduino_due as board;
 use board::entry;
 use board::prelude::*;
 
-use board::hal::clock::SystemClocks;
+use board::hal::clock::{ClockConfig, FastRCFreq, MainClockSource};
 use board::hal::comm;
 use board::hal::delay::Delay;
 use board::hal::watchdog::WdtBuilder;
-use board::pac::{CorePeripherals, Peripherals};
-use board::hal::time::Hertz;
+use board::pac::{CorePeripherals, Peripherals, UART};
+use board::hal::time::{Hertz, MegaHertz};
 
 use core::fmt::Write;
 
@@ -19,24 +19,49 @@ use core::fmt::Write;
 fn main() -> ! {
     let peripherals = Peripherals::take().unwrap();
     let core = CorePeripherals::take().unwrap();
-    let mut clocks = SystemClocks::new(peripherals.PMC, peripherals.SUPC);
+    let frozen = ClockConfig::new(
+        peripherals.PMC,
+        peripherals.SUPC,
+        peripherals.EFC0,
+        peripherals.EFC1,
+    )
+    .main_clock(MainClockSource::MainXtal(board::MAIN_CRYSTAL))
+    .master_clock_frequency(MegaHertz(84))
+    .freeze();
+    // Fall back to the fast RC oscillator if the crystal won't start
+    let (clock_error, (mut sysclk, clocks)) = match frozen {
+        Ok(frozen) => (None, frozen),
+        Err(failed) => (
+            Some(failed.error),
+            failed
+                .config
+                .main_clock(MainClockSource::FastRc(FastRCFreq::_12_MHZ))
+                .freeze()
+                .unwrap(),
+        ),
+    };
     let _ = WdtBuilder::from(peripherals.WDT).disable();
     let pins = board::Pins::new(
         peripherals.PIOA,
         peripherals.PIOB,
         peripherals.PIOC,
         peripherals.PIOD,
+        &mut sysclk,
     );
-    let mut delay = Delay::new(core.SYST, clocks.get_syscore());
+    let mut delay = Delay::new(core.SYST, &clocks);
 
-    let mut led_l = pins.led_l.into_push_pull_output();
+    let mut led_l = pins.d13_tiob0.into_push_pull_output();
 
     let mut uart = comm::Uart0::new(
         peripherals.UART,
         pins.d0_rx0.into_peripheral_a(),
         pins.d1_tx0.into_peripheral_a(),
+        sysclk.enable_peripheral_clock::<UART>().unwrap(),
     );
-    uart.set_baudrate(Hertz(57600), clocks.get_syscore());
+    uart.set_baudrate(Hertz(57600), &clocks);
+    if let Some(error) = clock_error {
+        writeln!(uart, "clock setup failed: {:?}", error).unwrap();
+    }
 
     loop {
         led_l.set_high();
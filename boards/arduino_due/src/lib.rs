@@ -15,6 +15,10 @@ pub use cortex_m_rt::entry;
 pub extern crate panic_halt;
 
 use hal::define_pins;
+use hal::time::Hertz;
+
+/// Frequency of the main crystal oscillator fitted to the board
+pub const MAIN_CRYSTAL: Hertz = Hertz(12_000_000);
 
 // The docs could be further improved with details of the specific channels etc
 define_pins!(
@@ -52,8 +56,8 @@ define_pins!(
     /// Digital 9, PWML4
     pin d9_pwml4 = c : 21,
 
-    /// Digital 10, NPCS0
-    pin d10_npcs0 = a : 28,
+    /// Digital 10, NPCS0 (also the SPI header's `npcs0`)
+    pin d10_npcs0 = a : 28 alias npcs0,
 
     /// Digital 11, TIOA8
     pin d11_tioa8 = d : 7,
@@ -61,11 +65,8 @@ define_pins!(
     /// Digital 12, TIOB8
     pin d12_tiob8 = d : 8,
 
-    /// Digital 13 (shared with `led_l`), TIOB0
-    pin d13_tiob0 = b : 27,
-
-    /// L (AMBER LED) (shared with `d13_tiob0`)
-    pin led_l = b : 27,
+    /// Digital 13, TIOB0, also driving the L (AMBER LED)
+    pin d13_tiob0 = b : 27 alias led_l,
 
     /// Digital 14, TX3 (USART3/Serial3)
     pin d14_tx3 = d : 4,
@@ -256,9 +257,6 @@ define_pins!(
     /// SCLK
     pin sclk = a : 27,
 
-    /// NPCS0
-    pin npcs0 = a : 28,
-
     /// NPCS3 (unconnected)
     pin npcs3 = b : 23,
 
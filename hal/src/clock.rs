@@ -1,15 +1,20 @@
 //! Configuring the system clock sources.
-//! You will typically need to create an instance of `SystemClocks`
-//! before you can set up most of the peripherals on the atsam3x target.
+//! You will typically need to describe the clock tree with a `ClockConfig`
+//! and `freeze()` it before you can set up most of the peripherals on the
+//! atsam3x target.  Freezing yields the `SystemClocks` controller along with
+//! a `Clocks` token recording the resulting frequencies, which drivers take
+//! in place of raw `Hertz` values.
 //! The other types in this module are used to enforce at compile time
 //! that the peripherals have been correctly configured.
+use crate::flash::{FlashController0, FlashController1};
 use crate::target_device;
 use crate::time::{Hertz, MegaHertz};
+use core::marker::PhantomData;
 use target_device::generic::Variant;
 use target_device::pmc::ckgr_mor::MOSCRCF_A::*;
 use target_device::pmc::pmc_mckr::{CSS_A::*, PRES_A::*};
 use target_device::supc::sr::OSCSEL_A::*;
-use target_device::{PMC, SUPC};
+use target_device::{EFC0, EFC1, PMC, SUPC};
 
 /// Valid frequency settings for the Fast RC oscillator
 pub type FastRCFreq = target_device::pmc::ckgr_mor::MOSCRCF_A;
@@ -17,6 +22,9 @@ pub type FastRCFreq = target_device::pmc::ckgr_mor::MOSCRCF_A;
 pub type ClockSource = target_device::pmc::pmc_mckr::CSS_A;
 /// Valid prescaler values for the system master clock
 pub type ClockPrescaler = target_device::pmc::pmc_mckr::PRES_A;
+/// Valid dividers from the master clock to a peripheral's clock.  Only the
+/// CAN controllers honour the divider; other peripherals always run at MCK.
+pub type PeripheralClockDivider = target_device::pmc::pmc_pcr::DIV_A;
 
 /// Oscillator sources that can be used by the slow clock.
 ///
@@ -24,6 +32,7 @@ pub type ClockPrescaler = target_device::pmc::pmc_mckr::PRES_A;
 /// the default clock for the system.  If more accurate timing is required,
 /// switch to the Low Power Crystal oscillator.  Once the LP Crystal osc. has
 /// been enabled, it is not possible to switch back.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SlowClockSource {
     /// Slow clock RC oscillator, runs at 32000Hz and less accurate
     LowPowerRC,
@@ -43,13 +52,46 @@ pub enum SlowClockSource {
 /// whatever external crystal or ceramic oscillator has been connected, in
 /// spite of the calibration on the 8MHz and 12MHz frequencies.
 ///
-/// The Main Clock Crystal frequency is determined by the board designer, but
-/// 12MHz is a common value.
+/// The Main Clock Crystal frequency is determined by the board designer, so
+/// it is supplied along with the source.  12MHz is a common value, and is
+/// required for USB.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MainClockSource {
     /// Internal, RC oscillator
     FastRc(FastRCFreq),
-    /// External Crystal or Ceramic oscillator
-    MainXtal,
+    /// External Crystal or Ceramic oscillator, running at the given
+    /// frequency (3-20MHz)
+    MainXtal(Hertz),
+}
+
+impl MainClockSource {
+    /// Return the frequency the main clock runs at when using this source
+    pub fn frequency(&self) -> Hertz {
+        match self {
+            MainClockSource::FastRc(_4_MHZ) => MegaHertz(4).into(),
+            MainClockSource::FastRc(_8_MHZ) => MegaHertz(8).into(),
+            MainClockSource::FastRc(_12_MHZ) => MegaHertz(12).into(),
+            MainClockSource::MainXtal(f) => *f,
+        }
+    }
+}
+
+/// Allowed difference between the main crystal frequency given by the board,
+/// and the frequency measured against the slow clock crystal, as a fraction
+/// of the crystal frequency.  This is enough to tell apart the common 8, 12
+/// and 16MHz crystals.
+const MAIN_XTAL_TOLERANCE_DIV: u32 = 16;
+
+/// Return the main clock frequency corresponding to a MAINF count, which is
+/// the number of main clock ticks in 16 slow clock cycles.
+fn mainf_to_frequency(mainf: u16, slck: Hertz) -> Hertz {
+    Hertz((mainf as u32 * slck.0) / 16)
+}
+
+/// Return whether a measured main clock frequency is close enough to the
+/// expected crystal frequency.
+fn main_xtal_matches(crystal: Hertz, measured: Hertz) -> bool {
+    measured.0.abs_diff(crystal.0) <= crystal.0 / MAIN_XTAL_TOLERANCE_DIV
 }
 
 /// Divider to apply to the master clock when using either PLLA or UPLL as
@@ -64,6 +106,7 @@ pub enum PllDiv {
 /// Configuration options for setting up the PLLA clock source.  The output
 /// frequency is the source clock frequency * (mula + 1)/diva.  The clock is
 /// disabled when mula = 0.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct PllAClockConfig {
     /// Clock multiplier minus one
     pub mula: u16,
@@ -75,9 +118,10 @@ pub struct PllAClockConfig {
 
 /// Configuration options for setting up the UPLL clock source.  To use USB,
 /// the main clock must be configured to use the external crystal oscillator,
-/// and it must be a 12MHz crystal.  The output frequency (TODO: unverified)
-/// is the source clock frequency * 40, although clocks using this as an input
+/// and it must be a 12MHz crystal.  The output frequency is the crystal
+/// frequency * 40, giving 480MHz, although clocks using this as an input
 /// may apply other dividers to it.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct UPllClockConfig {
     /// how many slow clock ticks are required for UPLL to settle
     pub count: u8,
@@ -89,9 +133,247 @@ impl From<u8> for UPllClockConfig {
     }
 }
 
+/// Highest master clock frequency the device is rated for
+pub const MCK_MAX: Hertz = Hertz(84_000_000);
+/// Lowest frequency the slow clock RC oscillator may run at
+const SLCK_MIN: Hertz = Hertz(20_000);
+/// Slow clock cycles to wait for an oscillator, the master clock or a
+/// frequency measurement before giving up.  This is well beyond the
+/// oscillator startup time programmed in `switch_main_oscillator`.
+pub(crate) const CLOCK_TIMEOUT_SLCK: u32 = 2048;
+
+/// Return how many times a status flag may be polled to wait out the given
+/// number of slow clock cycles, with the master clock running at `mck`.
+/// Each poll takes at least one master clock cycle, while the slow clock is
+/// never slower than `SLCK_MIN`.
+fn timeout_iterations(slck_cycles: u32, mck: Hertz) -> u32 {
+    let iterations = slck_cycles as u64 * mck.0 as u64 / SLCK_MIN.0 as u64;
+    iterations.min(u32::MAX as u64) as u32
+}
+
+/// Slow clock cycles to wait for a PLL to lock.  The PLL counters run for
+/// `count` * 8 slow clock cycles before reporting lock.
+fn pll_timeout_slck(count: u8) -> u32 {
+    2 * 8 * count as u32 + CLOCK_TIMEOUT_SLCK
+}
+/// Lowest frequency PLLA accepts at its input, after the DIVA divider
+pub const PLLA_INPUT_MIN: Hertz = Hertz(8_000_000);
+/// Highest frequency PLLA accepts at its input, after the DIVA divider
+pub const PLLA_INPUT_MAX: Hertz = Hertz(32_000_000);
+/// Lowest frequency PLLA can lock to at its output
+pub const PLLA_OUTPUT_MIN: Hertz = Hertz(84_000_000);
+/// Highest frequency PLLA can lock to at its output
+pub const PLLA_OUTPUT_MAX: Hertz = Hertz(192_000_000);
+
+/// Clock sources for the 48MHz USB clock (UOTGCK), used by the USB
+/// controller in full and low speed modes.  High speed mode always runs
+/// from the UTMI PLL.
+#[cfg(feature = "usb")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsbClockSource {
+    /// PLLA, which must run at a multiple of 48MHz
+    PllA,
+    /// UTMI PLL, divided down from 480MHz
+    UPll,
+}
+
+/// Frequency of the USB clock (UOTGCK)
+#[cfg(feature = "usb")]
+pub const USB_CLOCK: Hertz = Hertz(48_000_000);
+
+/// Return the USBDIV setting dividing the `source` clock down to exactly
+/// `USB_CLOCK`, if there is one.
+#[cfg(feature = "usb")]
+fn usb_divider(source: Hertz) -> Option<u8> {
+    let div = source.0 / USB_CLOCK.0;
+    if source.0 % USB_CLOCK.0 != 0 || !(1..=16).contains(&div) {
+        return None;
+    }
+    Some((div - 1) as u8)
+}
+
+/// Errors resulting from clock configuration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockError {
+    /// The requested master clock frequency is zero, or above `MCK_MAX`.
+    InvalidMasterClockFrequency,
+    /// No combination of PLLA multiplier and divider keeps the PLL within
+    /// its input and output frequency ranges while approaching the requested
+    /// master clock frequency.
+    PllAUnattainable,
+    /// The main crystal frequency measured against the slow clock crystal
+    /// doesn't match the frequency given for the board.  The main clock has
+    /// been left running from the 12MHz fast RC oscillator.
+    MainXtalMismatch(Hertz),
+    /// An oscillator didn't start up, or couldn't be selected as the main
+    /// clock, in time.  For the main crystal, this usually means it is
+    /// missing or dead.
+    OscillatorStartupTimeout,
+    /// PLLA didn't lock in time.
+    PllALockTimeout,
+    /// The UTMI PLL didn't lock in time.
+    UPllLockTimeout,
+    /// The master clock didn't report ready in time after a change of
+    /// source or prescaler.
+    MasterClockNotReady,
+    /// The measurement of the main clock frequency didn't complete in time.
+    MainFrequencyNotReady,
+    /// A programmable clock didn't report ready in time.
+    ProgrammableClockNotReady,
+    /// USB needs the main clock to run from an exactly 12MHz crystal.
+    #[cfg(feature = "usb")]
+    UsbRequires12MHzXtal,
+    /// The selected USB clock source can't be divided down to exactly
+    /// 48MHz.
+    #[cfg(feature = "usb")]
+    UsbClockUnattainable,
+}
+
+/// Error returned by `ClockConfig::freeze()`, handing back the clock
+/// configuration so startup code can report the failure and try again with
+/// different settings, such as the fast RC oscillator.
+pub struct FreezeError {
+    /// What went wrong
+    pub error: ClockError,
+    /// The configuration that failed to apply
+    pub config: ClockConfig,
+}
+
+impl core::fmt::Debug for FreezeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FreezeError")
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// The PLLA and master clock settings needed to run the master clock at
+/// (or as close as possible to) a requested frequency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PllASettings {
+    /// Multiplier and divider for PLLA
+    pub plla: PllAClockConfig,
+    /// Whether the master clock halves the PLLA output (PLLADIV2)
+    pub pll_div2: bool,
+    /// Master clock prescaler
+    pub prescaler: ClockPrescaler,
+    /// The master clock frequency these settings produce
+    pub mck: Hertz,
+}
+
+impl PllASettings {
+    /// Find the PLLA multiplier/divider, PLLADIV2 and master clock prescaler
+    /// that bring `mainck` closest to `target` while keeping PLLA within its
+    /// input and output frequency ranges.  Exact matches are preferred, and
+    /// between equally close candidates, the slower one is picked.  The
+    /// frequency actually achieved is reported in the `mck` field.
+    pub fn solve(mainck: Hertz, target: Hertz) -> Result<Self, ClockError> {
+        if target.0 == 0 || target.0 > MCK_MAX.0 {
+            return Err(ClockError::InvalidMasterClockFrequency);
+        }
+
+        const PRESCALERS: [(ClockPrescaler, u64); 8] = [
+            (CLK_1, 1),
+            (CLK_2, 2),
+            (CLK_3, 3),
+            (CLK_4, 4),
+            (CLK_8, 8),
+            (CLK_16, 16),
+            (CLK_32, 32),
+            (CLK_64, 64),
+        ];
+        let mainck = mainck.0 as u64;
+        let target = target.0 as u64;
+        let mut best: Option<(u64, PllASettings)> = None;
+
+        for &(prescaler, pres_div) in PRESCALERS.iter() {
+            for &pll_div2 in [false, true].iter() {
+                let post_div = pres_div << (pll_div2 as u64);
+                let pll_target = target * post_div;
+                for diva in 1..=255u64 {
+                    let pll_in = mainck / diva;
+                    if pll_in < PLLA_INPUT_MIN.0 as u64 {
+                        break;
+                    }
+                    if pll_in > PLLA_INPUT_MAX.0 as u64 {
+                        continue;
+                    }
+                    // Round the multiplier to the nearest integer, then make
+                    // sure the pll is still within its operating range
+                    let mul = (pll_target * diva + mainck / 2) / mainck;
+                    // mula is 11 bits, and a value of 0 disables the pll
+                    if !(2..=2048).contains(&mul) {
+                        continue;
+                    }
+                    let pll_out = mainck * mul / diva;
+                    if pll_out < PLLA_OUTPUT_MIN.0 as u64 || pll_out > PLLA_OUTPUT_MAX.0 as u64 {
+                        continue;
+                    }
+                    let mck = pll_out / post_div;
+                    if mck > MCK_MAX.0 as u64 {
+                        continue;
+                    }
+                    let error = mck.abs_diff(target);
+                    let better = match best {
+                        None => true,
+                        Some((best_error, ref settings)) => {
+                            error < best_error
+                                || (error == best_error && mck < settings.mck.0 as u64)
+                        }
+                    };
+                    if better {
+                        best = Some((
+                            error,
+                            PllASettings {
+                                plla: PllAClockConfig {
+                                    mula: (mul - 1) as u16,
+                                    diva: diva as u8,
+                                    count: 0x3f,
+                                },
+                                pll_div2,
+                                prescaler,
+                                mck: Hertz(mck as u32),
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+
+        best.map(|(_, settings)| settings)
+            .ok_or(ClockError::PllAUnattainable)
+    }
+}
+
+/// Return the PLLA multiplier and divider producing exactly `output` from
+/// `mainck`, within the PLL's input and output frequency ranges, if any do.
+fn plla_for_output(mainck: Hertz, output: Hertz, count: u8) -> Option<PllAClockConfig> {
+    if output.0 < PLLA_OUTPUT_MIN.0 || output.0 > PLLA_OUTPUT_MAX.0 {
+        return None;
+    }
+    let mainck = mainck.0 as u64;
+    let output = output.0 as u64;
+    (1..=255u64).find_map(|diva| {
+        let pll_in = mainck / diva;
+        if pll_in < PLLA_INPUT_MIN.0 as u64 || pll_in > PLLA_INPUT_MAX.0 as u64 {
+            return None;
+        }
+        let mul = output * diva / mainck;
+        if mainck * mul != output * diva || !(2..=2048).contains(&mul) {
+            return None;
+        }
+        Some(PllAClockConfig {
+            mula: (mul - 1) as u16,
+            diva: diva as u8,
+            count,
+        })
+    })
+}
+
 /// Identifier used for enabling/disabling the clock to that peripheral, as
 /// well as for controlling the peripher interrupt in the NVIC. Peripherals
-/// 0-8, and 10 are always clocked.
+/// 0-7 are always clocked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PeripheralID {
     /// ID  0, Supply controller, NVIC Interrupt, No PMC clock control
     Id00Supc = 0,
@@ -185,16 +467,389 @@ pub enum PeripheralID {
     Id44Can1,
 }
 
-/// `SystemClocks` encapsulates the PMC and SUPC clock hardware.
-/// It provides a type safe way to configure the system clocks.
-/// Initializing the `SystemClocks` instance configures the system to run at
-/// 84MHz by configuring Main clock to run at 12MHz, then setting PLLA to run
-/// at 14x the Main clock, and then setting Master Clock to divide PLLA by 2.
+/// A peripheral from the PAC whose clock is gated by the PMC.
+///
+/// The timer counter blocks each cover three peripheral IDs, one per
+/// channel, so they are not covered here.
+pub trait PmcPeripheral {
+    /// The peripheral's identifier
+    const PID: PeripheralID;
+}
+
+macro_rules! pmc_peripheral {
+    ($($(#[$attr:meta])* $Periph:ident => $Id:ident,)+) => {
+        $(
+            $(#[$attr])*
+            impl PmcPeripheral for target_device::$Periph {
+                const PID: PeripheralID = PeripheralID::$Id;
+            }
+        )+
+    };
+}
+
+pmc_peripheral! {
+    UART => Id08Uart,
+    #[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+    SMC => Id09SmcSdramc,
+    #[cfg(feature = "sam3x8h")]
+    SDRAMC => Id10Sdramc,
+    PIOA => Id11PioA,
+    PIOB => Id12PioB,
+    #[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+    PIOC => Id13PioC,
+    #[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+    PIOD => Id14PioD,
+    #[cfg(feature = "sam3x8h")]
+    PIOE => Id15PioE,
+    #[cfg(feature = "sam3x8h")]
+    PIOF => Id16PioF,
+    USART0 => Id17Usart0,
+    USART1 => Id18Usart1,
+    USART2 => Id19Usart2,
+    #[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+    USART3 => Id20Usart3,
+    HSMCI => Id21Hsmci,
+    TWI0 => Id22Twi0,
+    TWI1 => Id23Twi1,
+    SPI0 => Id24Spi0,
+    #[cfg(feature = "sam3x8h")]
+    SPI1 => Id25Spi1,
+    SSC => Id26Ssc,
+    PWM => Id36Pwm,
+    ADC => Id37Adc,
+    DACC => Id38Dacc,
+    DMAC => Id39Dmac,
+    UOTGHS => Id40Uotghs,
+    TRNG => Id41Trng,
+    #[cfg(not(any(feature = "sam3a4c", feature = "sam3a8c")))]
+    EMAC => Id42Emac,
+    CAN0 => Id43Can0,
+    CAN1 => Id44Can1,
+}
+
+/// Proof that the PMC clock for peripheral `P` is running.  Obtained from
+/// `SystemClocks::enable_peripheral_clock`, and handed back to
+/// `SystemClocks::disable_peripheral_clock` to stop the clock once the
+/// driver using it has been released.
+pub struct PeripheralClock<P> {
+    _peripheral: PhantomData<P>,
+}
+
+impl<P: PmcPeripheral> PeripheralClock<P> {
+    /// Return the identifier of the clocked peripheral.
+    pub fn id(&self) -> PeripheralID {
+        P::PID
+    }
+}
+
+/// Frequencies of the clock tree, captured when the clock configuration was
+/// frozen.
+///
+/// This is a cheap `Copy` token handed to peripheral drivers so that their
+/// baud rate and timing calculations are always derived from the clock tree
+/// that is actually running, rather than from hand-supplied values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Clocks {
+    slck: Hertz,
+    mainck: Hertz,
+    pllack: Hertz,
+    upllck: Hertz,
+    mck: Hertz,
+    #[cfg(feature = "usb")]
+    usbck: Hertz,
+    /// Right shift applied to MCK for CAN0 and CAN1, from their
+    /// `PeripheralClockDivider`
+    can_clock_shift: [u8; 2],
+}
+
+impl Clocks {
+    /// Slow clock (SLCK) frequency
+    pub fn slck(&self) -> Hertz {
+        self.slck
+    }
+
+    /// Main clock (MAINCK) frequency
+    pub fn mainck(&self) -> Hertz {
+        self.mainck
+    }
+
+    /// PLLA clock (PLLACK) frequency, zero if PLLA is disabled
+    pub fn pllack(&self) -> Hertz {
+        self.pllack
+    }
+
+    /// UTMI PLL clock (UPLLCK) frequency, zero if UPLL is disabled
+    pub fn upllck(&self) -> Hertz {
+        self.upllck
+    }
+
+    /// Master clock (MCK) frequency, which clocks the core and most
+    /// peripherals
+    pub fn mck(&self) -> Hertz {
+        self.mck
+    }
+
+    /// USB clock (UOTGCK) frequency, zero if the USB clock is disabled
+    #[cfg(feature = "usb")]
+    pub fn usbck(&self) -> Hertz {
+        self.usbck
+    }
+
+    /// Frequency of the clock driving the given peripheral, after any
+    /// divider set with `SystemClocks::set_peripheral_clock_divider`.
+    /// Drivers base their baud rate and bit timing calculations on this.
+    pub fn peripheral_clock(&self, pid: PeripheralID) -> Hertz {
+        match pid {
+            PeripheralID::Id43Can0 => Hertz(self.mck.0 >> self.can_clock_shift[0]),
+            PeripheralID::Id44Can1 => Hertz(self.mck.0 >> self.can_clock_shift[1]),
+            _ => self.mck,
+        }
+    }
+}
+
+/// Implemented by drivers whose settings are derived from the clock
+/// frequencies, such as baud rate dividers, so they can follow runtime
+/// changes to the clock tree made through `SystemClocks::reconfigure()`.
+pub trait ClockDependent {
+    /// Recompute any settings derived from the clock tree, which has just
+    /// changed to `clocks`.
+    fn clocks_changed(&mut self, clocks: &Clocks);
+}
+
+/// Snapshot of the configuration of the clock tree, as captured by
+/// `SystemClocks::clock_state`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockState {
+    /// Oscillator driving the main clock
+    pub main_clock: MainClockSource,
+    /// PLLA settings, if it was enabled
+    pub plla: Option<PllAClockConfig>,
+    /// UTMI PLL settings, if it was enabled
+    pub upll: Option<UPllClockConfig>,
+    /// Source of the master clock
+    pub master_source: ClockSource,
+    /// Prescaler applied to the master clock
+    pub master_prescaler: ClockPrescaler,
+    /// Whether the PLL feeding the master clock is divided by two
+    pub master_pll_div2: bool,
+}
+
+/// Builder describing the desired clock tree.
+///
+/// None of the settings are applied to the hardware until `freeze()` is
+/// called, which configures the slow clock, main clock, PLLA, UPLL and master
+/// clock in the correct order, then returns the `SystemClocks` controller
+/// and a `Clocks` token recording the resulting frequencies.
+///
+/// The flash controllers are owned by the clock configuration so that the
+/// flash wait states always match the master clock frequency.
+///
+/// The default configuration runs the master clock directly from a 12MHz
+/// main crystal oscillator, with both PLLs disabled.  Boards fitted with a
+/// different crystal should pass their frequency to `main_clock()`.
+pub struct ClockConfig {
+    pmc: PMC,
+    supc: SUPC,
+    efc0: EFC0,
+    efc1: EFC1,
+    slow_clock: SlowClockSource,
+    main_clock: MainClockSource,
+    plla: Option<PllAClockConfig>,
+    upll: Option<UPllClockConfig>,
+    master_source: ClockSource,
+    master_prescaler: Option<ClockPrescaler>,
+    master_pll_div2: bool,
+    master_frequency: Option<Hertz>,
+    #[cfg(feature = "usb")]
+    usb_clock: Option<UsbClockSource>,
+}
+
+impl ClockConfig {
+    /// Instantiate a clock configuration builder, taking ownership of the
+    /// clock hardware and the flash controllers.
+    pub fn new(pmc: PMC, supc: SUPC, efc0: EFC0, efc1: EFC1) -> Self {
+        Self {
+            pmc,
+            supc,
+            efc0,
+            efc1,
+            slow_clock: SlowClockSource::LowPowerRC,
+            main_clock: MainClockSource::MainXtal(MegaHertz(12).into()),
+            plla: None,
+            upll: None,
+            master_source: ClockSource::MAIN_CLK,
+            master_prescaler: None,
+            master_pll_div2: false,
+            master_frequency: None,
+            #[cfg(feature = "usb")]
+            usb_clock: None,
+        }
+    }
+
+    /// Select the oscillator used for the slow clock.
+    pub fn slow_clock(mut self, source: SlowClockSource) -> Self {
+        self.slow_clock = source;
+        self
+    }
+
+    /// Select the oscillator used for the main clock.
+    pub fn main_clock(mut self, source: MainClockSource) -> Self {
+        self.main_clock = source;
+        self
+    }
+
+    /// Enable PLLA with the provided multiplier and divider.
+    pub fn plla(mut self, config: PllAClockConfig) -> Self {
+        self.plla = Some(config);
+        self
+    }
+
+    /// Enable the UTMI PLL.
+    pub fn upll(mut self, config: UPllClockConfig) -> Self {
+        self.upll = Some(config);
+        self
+    }
+
+    /// Select which clock source the master clock should use, along with
+    /// its prescaler and, for PLL sources, whether to halve the PLL output.
+    pub fn master_clock(
+        mut self,
+        source: ClockSource,
+        prescaler: Option<ClockPrescaler>,
+        pll_div2: bool,
+    ) -> Self {
+        self.master_source = source;
+        self.master_prescaler = prescaler;
+        self.master_pll_div2 = pll_div2;
+        self.master_frequency = None;
+        self
+    }
+
+    /// Run the master clock from PLLA, with the PLL and prescaler settings
+    /// chosen by `PllASettings::solve()` to get as close as possible to the
+    /// requested frequency.  This overrides any `plla()` and
+    /// `master_clock()` settings.
+    pub fn master_clock_frequency<I: Into<Hertz>>(mut self, frequency: I) -> Self {
+        self.master_frequency = Some(frequency.into());
+        self
+    }
+
+    /// Clock the USB controller, running the 48MHz USB clock from `source`.
+    /// The UTMI PLL is always needed by the USB transceiver, so it is
+    /// enabled with a default startup count if `upll()` wasn't given.
+    #[cfg(feature = "usb")]
+    pub fn usb_clock(mut self, source: UsbClockSource) -> Self {
+        self.usb_clock = Some(source);
+        if self.upll.is_none() {
+            self.upll = Some(UPllClockConfig { count: 3 });
+        }
+        self
+    }
+
+    /// Apply the configuration to the hardware, returning the clock
+    /// controller for runtime adjustments, and the frequencies of the
+    /// resulting clock tree.
+    ///
+    /// Fails without touching the hardware if a requested master clock
+    /// frequency cannot be produced from the selected main clock.  If an
+    /// oscillator or PLL doesn't come up in time, the master clock is left
+    /// running from the main clock, and the configuration is handed back
+    /// with the error.
+    pub fn freeze(mut self) -> Result<(SystemClocks, Clocks), FreezeError> {
+        if let Some(frequency) = self.master_frequency {
+            let settings = match PllASettings::solve(self.main_clock.frequency(), frequency) {
+                Ok(settings) => settings,
+                Err(error) => {
+                    return Err(FreezeError {
+                        error,
+                        config: self,
+                    })
+                }
+            };
+            self.plla = Some(settings.plla);
+            self.master_source = ClockSource::PLLA_CLK;
+            self.master_prescaler = Some(settings.prescaler);
+            self.master_pll_div2 = settings.pll_div2;
+        }
+
+        let mut sysclk = SystemClocks {
+            pmc: self.pmc,
+            supc: self.supc,
+            efc0: FlashController0::new(self.efc0),
+            efc1: FlashController1::new(self.efc1),
+            issued_peripheral_clocks: 0,
+            main_xtal: self.main_clock.frequency(),
+        };
+        if self.slow_clock == SlowClockSource::LowPowerXtal32Khz {
+            sysclk.enable_slow_clock_xtal();
+        }
+
+        let (plla, upll) = (self.plla, self.upll);
+        let (master_source, master_prescaler, master_pll_div2) = (
+            self.master_source,
+            self.master_prescaler,
+            self.master_pll_div2,
+        );
+        let result = sysclk
+            .set_main_clock_source(self.main_clock)
+            .and_then(|_| match plla {
+                Some(plla) => sysclk.configure_plla(plla),
+                None => Ok(()),
+            })
+            .and_then(|_| match upll {
+                Some(upll) => sysclk.enable_upll(upll),
+                None => Ok(()),
+            })
+            .and_then(|_| {
+                sysclk.set_master_clock_source_and_prescaler(
+                    master_source,
+                    master_prescaler,
+                    master_pll_div2,
+                )
+            });
+        #[cfg(feature = "usb")]
+        let result = match self.usb_clock {
+            Some(source) => result.and_then(|_| sysclk.enable_usb_clock(source)),
+            None => result,
+        };
+
+        match result {
+            Ok(()) => {
+                let clocks = sysclk.clocks();
+                Ok((sysclk, clocks))
+            }
+            Err(error) => Err(FreezeError {
+                error,
+                config: ClockConfig {
+                    pmc: sysclk.pmc,
+                    supc: sysclk.supc,
+                    efc0: sysclk.efc0.free(),
+                    efc1: sysclk.efc1.free(),
+                    ..self
+                },
+            }),
+        }
+    }
+}
+
+/// `SystemClocks` encapsulates the PMC and SUPC clock hardware, along with
+/// the flash controllers whose wait states depend on the master clock.
+/// It provides a type safe way to adjust the system clocks at runtime, once
+/// the initial clock tree has been set up by `ClockConfig::freeze()`.
 pub struct SystemClocks {
     /// Power Management Controller
     pub pmc: PMC,
     /// Power Supply Controller
     pub supc: SUPC,
+    /// Embedded flash controller 0
+    pub efc0: FlashController0,
+    /// Embedded flash controller 1
+    pub efc1: FlashController1,
+    /// Peripheral clocks with an outstanding `PeripheralClock` token, one
+    /// bit per `PeripheralID`
+    issued_peripheral_clocks: u64,
+    /// Frequency of the main crystal oscillator fitted to the board
+    main_xtal: Hertz,
 }
 
 impl core::ops::Deref for SystemClocks {
@@ -212,59 +867,52 @@ impl core::ops::DerefMut for SystemClocks {
 }
 
 impl SystemClocks {
-    /// Select the specified slow clock oscillator, and clock the system to run
-    /// at that frequency.
-    pub fn with_slow_clk(pmc: PMC, supc: SUPC, use_external_crystal: bool) -> Self {
-        let mut clk = Self {
-            pmc,
-            supc,
-        };
-        if use_external_crystal {
-            clk.enable_slow_clock_xtal();
-        }
-        clk.set_master_clock_source_and_prescaler(ClockSource::SLOW_CLK, None, false);
-        clk
-    }
-
-    /// Set the main clock source, and clock the system to run at that frequency.
-    pub fn with_main_clk(pmc: PMC, supc: SUPC, source: MainClockSource) -> Self {
-        let mut clk = Self {
-            pmc,
-            supc,
-        };
-        clk.set_main_clock_source(source);
-        clk.set_master_clock_source_and_prescaler(ClockSource::MAIN_CLK, None, false);
-        clk
-    }
-
-    /// Configure the main clock to run off the main oscillator crystal, then
-    /// configure PLLA to run at 14x that, set the system to run at half the
-    /// frequency of PLLA, and if the usb feature is enabled, enable UPLL as well.
-    pub fn with_plla_clk(pmc: PMC, supc: SUPC) -> Self {
-        let mut clk = Self::with_main_clk(pmc, supc, MainClockSource::MainXtal);
-
-        clk.configure_plla(PllAClockConfig {
-            mula: 14 - 1,
-            diva: 1,
-            count: 0x3f,
-        });
-        clk.set_master_clock_source_and_prescaler(ClockSource::PLLA_CLK, None, true);
-
-        #[cfg(feature = "usb")]
-        clk.enable_upll(3);
-
-        clk
+    /// Make a runtime change to the clock tree with `change`, then have each
+    /// of the `drivers` recompute its clock-derived settings.  Interrupts are
+    /// disabled throughout, so no driver runs with settings computed for the
+    /// old clocks.
+    ///
+    /// The drivers are notified even if the change fails part way, since
+    /// the clocks may have changed anyway.  Returns the frequencies of the
+    /// new clock tree.
+    pub fn reconfigure<F>(
+        &mut self,
+        drivers: &mut [&mut dyn ClockDependent],
+        change: F,
+    ) -> Result<Clocks, ClockError>
+    where
+        F: FnOnce(&mut SystemClocks) -> Result<(), ClockError>,
+    {
+        cortex_m::interrupt::free(|_| {
+            let result = change(self);
+            let clocks = self.clocks();
+            for driver in drivers.iter_mut() {
+                driver.clocks_changed(&clocks);
+            }
+            result.map(|_| clocks)
+        })
     }
 
-    /// Set the main clock source, and clock the system to run at that frequency.
-    /// TODO: Either we have a bug in how we configure PLLA, or we have a bug in
-    /// how to derive SysTick intervals based on PLLA configuration, either way
-    /// until that's fixed, we'll default to using main clk.
-    pub fn new(pmc: PMC, supc: SUPC) -> Self {
-        Self::with_main_clk(pmc, supc, MainClockSource::MainXtal)
+    /// Capture the frequencies of the clock tree as it is currently
+    /// configured.  This needs to be called again after any runtime change
+    /// to the clock configuration.
+    pub fn clocks(&self) -> Clocks {
+        Clocks {
+            slck: self.get_slow_clock_rate(),
+            mainck: self.get_main_clock_rate(),
+            pllack: self.get_plla_clock_rate(),
+            upllck: self.get_upll_clock_rate(),
+            mck: self.get_syscore(),
+            #[cfg(feature = "usb")]
+            usbck: self.get_usb_clock_rate(),
+            can_clock_shift: [
+                self.get_peripheral_clock_divider(PeripheralID::Id43Can0) as u8,
+                self.get_peripheral_clock_divider(PeripheralID::Id44Can1) as u8,
+            ],
+        }
     }
 
-    /// Return the frequency that the main clock is operating at
+    /// Return the frequency that the slow clock is operating at
     pub fn get_slow_clock_rate(&self) -> Hertz {
         match self.supc.sr.read().oscsel().variant() {
             RC => Hertz(32000),
@@ -275,7 +923,7 @@ impl SystemClocks {
     /// Return the frequency that the main clock is operating at
     pub fn get_main_clock_rate(&self) -> Hertz {
         if self.ckgr_mor.read().moscsel().bits() {
-            MegaHertz(12).into()
+            self.main_xtal
         } else {
             match self.ckgr_mor.read().moscrcf().variant() {
                 Variant::Val(_4_MHZ) => MegaHertz(4).into(),
@@ -288,59 +936,137 @@ impl SystemClocks {
 
     /// Return the frequency that the main clock is operating at, based
     /// on the slow clock rate.
-    pub fn get_main_clock_rate_calibrated(&self) -> Hertz {
+    pub fn get_main_clock_rate_calibrated(&self) -> Result<Hertz, ClockError> {
         // Wait until mainf has been calibrated since the last change of the
         // main clock
-        while !self.ckgr_mcfr.read().mainfrdy().bits() {}
+        self.wait_until(CLOCK_TIMEOUT_SLCK, ClockError::MainFrequencyNotReady, |s| {
+            s.ckgr_mcfr.read().mainfrdy().bits()
+        })?;
 
         // mainf is how many times the main clock ticks during the count of 16
         // slow clock cycles
-        let mainf = self.ckgr_mcfr.read().mainf().bits() as u32;
-        let mainf_freq = (mainf * self.get_slow_clock_rate().0) / 16;
-        Hertz(mainf_freq)
+        Ok(mainf_to_frequency(
+            self.ckgr_mcfr.read().mainf().bits(),
+            self.get_slow_clock_rate(),
+        ))
     }
 
     /// Return the frequency that the plla clock is operating at
     pub fn get_plla_clock_rate(&self) -> Hertz {
         // plla clock = mainck * (mula + 1)/diva
+        // A multiplier or divider of zero disables the pll
+        let pllar = self.ckgr_pllar.read();
+        if pllar.mula().bits() == 0 || pllar.diva().bits() == 0 {
+            return Hertz(0);
+        }
         let mut tmp_clk = self.get_main_clock_rate();
-        tmp_clk.0 *= (self.ckgr_pllar.read().mula().bits() + 1) as u32;
-        tmp_clk.0 /= self.ckgr_pllar.read().diva().bits() as u32;
+        tmp_clk.0 *= (pllar.mula().bits() + 1) as u32;
+        tmp_clk.0 /= pllar.diva().bits() as u32;
         tmp_clk
     }
 
     /// Return the frequency that the upll clock is operating at
     pub fn get_upll_clock_rate(&self) -> Hertz {
-        // upll clock = mainck * 40
-        // but it's only valid if mainck == 12MHz
-        let mut tmp_clk = self.get_main_clock_rate();
-        tmp_clk.0 *= 40;
-        tmp_clk
+        if !self.ckgr_uckr.read().upllen().bits() {
+            return Hertz(0);
+        }
+        // upll clock = main crystal * 40
+        // but it's only valid if the crystal is 12MHz
+        Hertz(self.main_xtal.0 * 40)
+    }
+
+    /// Return the frequency that the USB clock is operating at
+    #[cfg(feature = "usb")]
+    pub fn get_usb_clock_rate(&self) -> Hertz {
+        if !self.pmc_scsr.read().uotgclk().bits() {
+            return Hertz(0);
+        }
+        let usb = self.pmc_usb.read();
+        let source = if usb.usbs().bits() {
+            self.get_upll_clock_rate()
+        } else {
+            self.get_plla_clock_rate()
+        };
+        Hertz(source.0 / (usb.usbdiv().bits() as u32 + 1))
+    }
+
+    /// Run the 48MHz USB clock from `source`, and enable it.  The source
+    /// has to be running already.
+    ///
+    /// Fails if the main clock isn't running from an exactly 12MHz crystal,
+    /// or if the source can't be divided down to 48MHz.
+    #[cfg(feature = "usb")]
+    pub fn enable_usb_clock(&mut self, source: UsbClockSource) -> Result<(), ClockError> {
+        if self.main_clock_source() != MainClockSource::MainXtal(MegaHertz(12).into()) {
+            return Err(ClockError::UsbRequires12MHzXtal);
+        }
+        let source_clock = match source {
+            UsbClockSource::PllA => self.get_plla_clock_rate(),
+            UsbClockSource::UPll => self.get_upll_clock_rate(),
+        };
+        let div = usb_divider(source_clock).ok_or(ClockError::UsbClockUnattainable)?;
+
+        self.pmc_usb.write(|w| unsafe {
+            w.usbs()
+                .bit(source == UsbClockSource::UPll)
+                .usbdiv()
+                .bits(div)
+        });
+        self.pmc_scer.write_with_zero(|w| w.uotgclk().set_bit());
+        Ok(())
+    }
+
+    /// Disable the USB clock.
+    #[cfg(feature = "usb")]
+    pub fn disable_usb_clock(&mut self) {
+        self.pmc_scdr.write_with_zero(|w| w.uotgclk().set_bit());
     }
 
     /// Return the frequency that the master clock is operating at
-    pub fn get_syscore(&mut self) -> Hertz {
+    pub fn get_syscore(&self) -> Hertz {
         /* Determine clock frequency according to clock register values */
-        let mut clk_unscaled: Hertz = match self.pmc_mckr.read().css().variant() {
+        let mckr = self.pmc_mckr.read();
+        let source = mckr.css().variant();
+        let pll_div2 = match source {
+            PLLA_CLK => mckr.plladiv2().bits(),
+            UPLL_CLK => mckr.uplldiv2().bits(),
+            _ => false,
+        };
+        self.get_master_clock_rate_for(source, mckr.pres().variant(), pll_div2)
+    }
+
+    /// Return the frequency that the master clock would operate at with the
+    /// given settings.
+    fn get_master_clock_rate_for(
+        &self,
+        source: ClockSource,
+        prescaler: ClockPrescaler,
+        pll_div2: bool,
+    ) -> Hertz {
+        let mut clk_unscaled: Hertz = match source {
             SLOW_CLK => self.get_slow_clock_rate(),
             MAIN_CLK => self.get_main_clock_rate(),
             PLLA_CLK => self.get_plla_clock_rate(),
             UPLL_CLK => self.get_upll_clock_rate(),
         };
         // Apply pll-specific divider if set
-        if self.pmc_mckr.read().css().variant() == PLLA_CLK {
-            clk_unscaled.0 /= 1 << (self.pmc_mckr.read().plladiv2().bits() as u8);
-        }
-        if self.pmc_mckr.read().css().variant() == PLLA_CLK {
-            clk_unscaled.0 /= 1 << (self.pmc_mckr.read().uplldiv2().bits() as u8);
+        if pll_div2 && (source == PLLA_CLK || source == UPLL_CLK) {
+            clk_unscaled.0 /= 2;
         }
         // Apply prescaler
-        match self.pmc_mckr.read().pres().variant() {
+        match prescaler {
             CLK_3 => Hertz(clk_unscaled.0 / 3),
             x => Hertz(clk_unscaled.0 >> (x as u8)),
         }
     }
 
+    /// Program both flash controllers with the minimum wait states that
+    /// are safe at the given master clock frequency.
+    fn set_flash_wait_states_for(&mut self, mck: Hertz) {
+        self.efc0.set_wait_states_for(mck);
+        self.efc1.set_wait_states_for(mck);
+    }
+
     /// Slow clock is always enabled, but is sourced from a low-accuracy RC
     /// oscillator.  This enables the more accurate crystal oscillator and
     /// switch to use that as the slow clock source.  Once the crystal
@@ -353,7 +1079,9 @@ impl SystemClocks {
     }
 
     /// Disabling the main clock is usually only done to enter low power/idle
-    /// states.  It may only be re-enabled by an interrupt or rebooting.
+    /// states.  It may only be re-enabled by an interrupt or rebooting.  To stop
+    /// the clocks and resume afterwards, use the low-power modes in the
+    /// `power` module instead.
     pub fn disable_main_clock(&mut self) {
         self.ckgr_mor.modify(|_, w| {
             w.key()
@@ -365,90 +1093,362 @@ impl SystemClocks {
         });
     }
 
-    /// Select the oscillator source to use for the main clock
-    pub fn set_main_clock_source(&mut self, source: MainClockSource) {
-        // TODO: if master clock source is PLLA or UPLL, and the current
-        // MainClockSource is FastRC, a special procedure is required:
-        //   - save the current master clock source selection
-        //   - switch master clock to main
-        //   - make the change to the main clock
-        //   - wait for the change to settle
-        //   - save the pll state
-        //   - disable the pll
-        //   - restore the pll state
-        //     (the count value cannot be recovered from current state, though)
-        //   - wait for the pll to settle
-        //   - restore the master clock source selection
+    /// Select the oscillator source to use for the main clock.
+    ///
+    /// If the master clock is running from one of the PLLs, it is parked on
+    /// the main clock while the oscillator is switched, and then returned
+    /// to its previous source.  PLLA is re-solved for the new oscillator:
+    /// when it drives the master clock, for the master clock frequency
+    /// closest to the previous one, and otherwise for the same output
+    /// frequency.  The flash wait states follow the master clock.  If PLLA
+    /// can't be solved, it is left disabled, the master clock is left on
+    /// the main clock, and `ClockError::PllAUnattainable` is returned.
+    ///
+    /// When switching to the crystal while the slow clock runs from its own
+    /// crystal, the crystal frequency is checked against the frequency
+    /// measured by the PMC.  The slow RC oscillator is too inaccurate to make
+    /// this check, so it is skipped in that case.  If the crystal doesn't
+    /// start up in time, or doesn't match its expected frequency, the main
+    /// clock falls back to the 12MHz fast RC oscillator and an error is
+    /// returned.
+    pub fn set_main_clock_source(&mut self, source: MainClockSource) -> Result<(), ClockError> {
+        let mckr = self.pmc_mckr.read();
+        let master_source = mckr.css().variant();
+        let master_prescaler = mckr.pres().variant();
+        let master_pll_div2 = match master_source {
+            PLLA_CLK => mckr.plladiv2().bits(),
+            UPLL_CLK => mckr.uplldiv2().bits(),
+            _ => false,
+        };
+        let on_pll = master_source == PLLA_CLK || master_source == UPLL_CLK;
+        let previous_mck = self.get_syscore();
+        let plla = self
+            .plla_config()
+            .map(|config| (config.count, self.get_plla_clock_rate()));
 
+        // Park the master clock on the main clock, so it doesn't glitch
+        // while the PLL it runs from loses lock
+        if on_pll {
+            self.set_master_clock_source_and_prescaler(MAIN_CLK, None, false)?;
+        }
+
+        // The master clock may speed up with the main clock, so the flash
+        // has to keep up with the new oscillator, or the fast RC
+        // oscillator it falls back to
+        let fastest = [
+            self.get_syscore(),
+            source.frequency(),
+            MainClockSource::FastRc(_12_MHZ).frequency(),
+        ]
+        .iter()
+        .map(|f| f.0)
+        .max()
+        .unwrap_or(0);
+        self.set_flash_wait_states_for(Hertz(fastest));
+
+        let result = self
+            .switch_main_oscillator(source)
+            .and_then(|_| match source {
+                MainClockSource::MainXtal(crystal) => self.check_main_xtal(crystal),
+                MainClockSource::FastRc(_) => Ok(()),
+            });
+        if result.is_err() {
+            self.switch_main_oscillator(MainClockSource::FastRc(_12_MHZ))?;
+        }
+        let mck = self.get_syscore();
+        self.set_flash_wait_states_for(mck);
+
+        // PLLA follows the main clock, so its multiplier and divider have
+        // to be solved again for the new oscillator.  The UTMI PLL runs
+        // directly from the crystal, which is kept running while it is
+        // enabled.
+        if let Some((count, plla_rate)) = plla {
+            self.disable_plla();
+            let mainck = self.get_main_clock_rate();
+            if master_source == PLLA_CLK {
+                let settings = PllASettings::solve(mainck, previous_mck)?;
+                self.configure_plla(PllAClockConfig {
+                    count,
+                    ..settings.plla
+                })?;
+                self.set_master_clock_source_and_prescaler(
+                    PLLA_CLK,
+                    Some(settings.prescaler),
+                    settings.pll_div2,
+                )?;
+            } else {
+                let config = plla_for_output(mainck, plla_rate, count)
+                    .ok_or(ClockError::PllAUnattainable)?;
+                self.configure_plla(config)?;
+            }
+        }
+
+        if master_source == UPLL_CLK {
+            self.set_master_clock_source_and_prescaler(
+                master_source,
+                Some(master_prescaler),
+                master_pll_div2,
+            )?;
+        }
+        result
+    }
+
+    /// Check the main crystal runs at the expected frequency, by measuring
+    /// it against the slow clock crystal.
+    fn check_main_xtal(&self, crystal: Hertz) -> Result<(), ClockError> {
+        if !self.supc.sr.read().oscsel().bits() {
+            return Ok(());
+        }
+
+        let measured = self.get_main_clock_rate_calibrated()?;
+        if main_xtal_matches(crystal, measured) {
+            Ok(())
+        } else {
+            Err(ClockError::MainXtalMismatch(measured))
+        }
+    }
+
+    /// Switch the main clock between the oscillators, without regard for
+    /// any clocks derived from it.  If the crystal doesn't start up, it is
+    /// stopped again and the main clock is left as it was.
+    fn switch_main_oscillator(&mut self, source: MainClockSource) -> Result<(), ClockError> {
         // Crystal oscillator startup time
         // startup cycles = 8 * startup_time / SLCK
         let startup_time: u8 = 8;
 
         // To ensure a smooth transition in case other clocks are running off
-        // main clock, we enable both clocks, wait out the startup time,
-        // switch to the desired clock, then disable the unused clock
-        self.ckgr_mor.modify(|_, w| unsafe {
-            w.key()
-                .passwd()
-                .moscrcen()
-                .set_bit()
-                .moscxten()
-                .set_bit()
-                .moscxtst()
-                .bits(startup_time)
-        });
-        // Wait until RC startup time runs out
-        while !self.pmc_sr.read().moscrcs().bits() {}
-        // Wait until Xtal startup time runs out
-        while !self.pmc_sr.read().moscxts().bits() {}
-
+        // main clock, we enable the desired clock, wait out its startup time,
+        // switch to it, then disable the unused clock
         match source {
             MainClockSource::FastRc(f) => {
+                self.ckgr_mor
+                    .modify(|_, w| w.key().passwd().moscrcen().set_bit());
+                // Wait until RC startup time runs out
+                self.wait_for_oscillator(|s| s.pmc_sr.read().moscrcs().bits())?;
+
                 // Set RC osc frequency
                 self.ckgr_mor
                     .modify(|_, w| w.key().passwd().moscrcf().variant(f));
                 // Let RC osc stabilize at new frequency
-                while !self.pmc_sr.read().moscrcs().bits() {}
+                self.wait_for_oscillator(|s| s.pmc_sr.read().moscrcs().bits())?;
 
                 // Switch main clock to RC osc
                 self.ckgr_mor
                     .modify(|_, w| w.key().passwd().moscsel().clear_bit());
                 // Wait until oscillator selection reports ready
                 // 0 = done, 1 = in progress
-                while !self.pmc_sr.read().moscsels().bits() {}
+                self.wait_for_oscillator(|s| s.pmc_sr.read().moscsels().bits())?;
 
-                // Disable unused xtal oscillator
+                // The failure detector watches the xtal oscillator, so it
+                // has to be stopped before the xtal is
                 self.ckgr_mor
-                    .modify(|_, w| w.key().passwd().moscxten().clear_bit());
+                    .modify(|_, w| w.key().passwd().cfden().clear_bit());
+
+                // Disable the xtal oscillator, unless the UTMI PLL needs it
+                if !self.ckgr_uckr.read().upllen().bits() {
+                    self.ckgr_mor
+                        .modify(|_, w| w.key().passwd().moscxten().clear_bit());
+                }
             }
-            MainClockSource::MainXtal => {
+            MainClockSource::MainXtal(crystal) => {
+                self.ckgr_mor.modify(|_, w| unsafe {
+                    w.key()
+                        .passwd()
+                        .moscxten()
+                        .set_bit()
+                        .moscxtst()
+                        .bits(startup_time)
+                });
+                // Wait until Xtal startup time runs out
+                if let Err(error) = self.wait_for_oscillator(|s| s.pmc_sr.read().moscxts().bits()) {
+                    // Stop the missing or dead xtal again
+                    self.ckgr_mor
+                        .modify(|_, w| w.key().passwd().moscxten().clear_bit());
+                    return Err(error);
+                }
+
+                self.main_xtal = crystal;
                 self.ckgr_mor
                     .modify(|_, w| w.key().passwd().moscsel().set_bit());
                 // Wait until oscillator selection reports ready
                 // 0 = done, 1 = in progress
-                while !self.pmc_sr.read().moscsels().bits() {}
+                self.wait_for_oscillator(|s| s.pmc_sr.read().moscsels().bits())?;
+
+                // Fall back to the fast RC oscillator if the xtal stops
+                self.ckgr_mor
+                    .modify(|_, w| w.key().passwd().cfden().set_bit());
 
                 // Disable unused RC oscillator
                 self.ckgr_mor
                     .modify(|_, w| w.key().passwd().moscrcen().clear_bit());
             }
         }
+        Ok(())
+    }
+
+    /// Wait until a main clock oscillator reports ready.
+    fn wait_for_oscillator<F>(&self, ready: F) -> Result<(), ClockError>
+    where
+        F: Fn(&Self) -> bool,
+    {
+        self.wait_until(
+            CLOCK_TIMEOUT_SLCK,
+            ClockError::OscillatorStartupTimeout,
+            ready,
+        )
+    }
+
+    /// Return the oscillator the main clock is currently running from.
+    pub fn main_clock_source(&self) -> MainClockSource {
+        let mor = self.ckgr_mor.read();
+        if mor.moscsel().bits() {
+            MainClockSource::MainXtal(self.main_xtal)
+        } else {
+            match mor.moscrcf().variant() {
+                Variant::Val(f) => MainClockSource::FastRc(f),
+                Variant::Res(_) => unreachable!(),
+            }
+        }
+    }
+
+    /// Return the current PLLA settings, or `None` if it is disabled.
+    pub fn plla_config(&self) -> Option<PllAClockConfig> {
+        let pllar = self.ckgr_pllar.read();
+        if pllar.mula().bits() == 0 || pllar.diva().bits() == 0 {
+            return None;
+        }
+        Some(PllAClockConfig {
+            mula: pllar.mula().bits(),
+            diva: pllar.diva().bits(),
+            count: pllar.pllacount().bits(),
+        })
+    }
+
+    /// Return the current UTMI PLL settings, or `None` if it is disabled.
+    pub fn upll_config(&self) -> Option<UPllClockConfig> {
+        let uckr = self.ckgr_uckr.read();
+        if !uckr.upllen().bits() {
+            return None;
+        }
+        Some(UPllClockConfig {
+            count: uckr.upllcount().bits(),
+        })
+    }
+
+    /// Enable the PMC interrupt raised when the clock failure detector finds
+    /// the main crystal has stopped.  The PMC interrupt must also be
+    /// unmasked in the NVIC.
+    pub fn listen_clock_failure(&mut self) {
+        self.pmc_ier.write_with_zero(|w| w.cfdev().set_bit());
+    }
+
+    /// Disable the PMC interrupt for clock failures.
+    pub fn unlisten_clock_failure(&mut self) {
+        self.pmc_idr.write_with_zero(|w| w.cfdev().set_bit());
+    }
+
+    /// Check whether the clock failure detector has switched the main clock
+    /// to the fast RC oscillator, typically from the PMC interrupt handler.
+    ///
+    /// When the main crystal stops, the hardware moves the main clock to
+    /// the fast RC oscillator, and the master clock off any PLL onto the
+    /// main clock.  This returns the frequencies of the resulting clock
+    /// tree, so peripheral timings can be re-derived from them, and clears
+    /// the failure so it is only reported once.
+    pub fn clock_failure(&mut self) -> Option<Clocks> {
+        let sr = self.pmc_sr.read();
+        // The event flag is cleared by any read of the status register, so
+        // also check the fault output, which latches until cleared
+        if !sr.cfdev().bits() && !sr.fos().bits() {
+            return None;
+        }
+
+        // Record the switch to the fast RC oscillator, and stop watching the
+        // failed crystal
+        self.ckgr_mor
+            .modify(|_, w| w.key().passwd().moscsel().clear_bit().cfden().clear_bit());
+        self.pmc_focr.write_with_zero(|w| w.foclr().set_bit());
+
+        let clocks = self.clocks();
+        self.set_flash_wait_states_for(clocks.mck());
+        Some(clocks)
+    }
+
+    /// Capture the configuration of the clock tree, so it can be returned
+    /// to later with `restore_clock_state`.
+    pub fn clock_state(&self) -> ClockState {
+        let mckr = self.pmc_mckr.read();
+        let master_source = mckr.css().variant();
+        ClockState {
+            main_clock: self.main_clock_source(),
+            plla: self.plla_config(),
+            upll: self.upll_config(),
+            master_source,
+            master_prescaler: mckr.pres().variant(),
+            master_pll_div2: match master_source {
+                PLLA_CLK => mckr.plladiv2().bits(),
+                UPLL_CLK => mckr.uplldiv2().bits(),
+                _ => false,
+            },
+        }
+    }
+
+    /// Drop the master clock to run directly from the fast RC oscillator,
+    /// stopping the crystal oscillator and both PLLs to save power.
+    ///
+    /// Returns the previous configuration of the clock tree, which can be
+    /// handed to `restore_clock_state` to bring the clocks back up, along
+    /// with the frequencies of the new clock tree.
+    pub fn run_from_fast_rc(
+        &mut self,
+        frequency: FastRCFreq,
+    ) -> Result<(ClockState, Clocks), ClockError> {
+        let state = self.clock_state();
+
+        self.set_master_clock_source_and_prescaler(MAIN_CLK, Some(CLK_1), false)?;
+        self.disable_plla();
+        self.disable_upll();
+        self.switch_main_oscillator(MainClockSource::FastRc(frequency))?;
+
+        Ok((state, self.clocks()))
+    }
+
+    /// Return the clock tree to a configuration captured by `clock_state`,
+    /// returning the frequencies of the restored clock tree.  If an
+    /// oscillator or PLL doesn't come back up in time, the master clock is
+    /// left running from the main clock.
+    pub fn restore_clock_state(&mut self, state: ClockState) -> Result<Clocks, ClockError> {
+        self.set_master_clock_source_and_prescaler(MAIN_CLK, None, false)?;
+        self.switch_main_oscillator(state.main_clock)?;
+        match state.plla {
+            Some(plla) => self.configure_plla(plla)?,
+            None => self.disable_plla(),
+        }
+        match state.upll {
+            Some(upll) => self.enable_upll(upll)?,
+            None => self.disable_upll(),
+        }
+        self.set_master_clock_source_and_prescaler(
+            state.master_source,
+            Some(state.master_prescaler),
+            state.master_pll_div2,
+        )?;
+        Ok(self.clocks())
     }
 
     /// Disable PLLA by setting the clock multiplier to zero
     // Setting the multiplier to 0 disables it
     pub fn disable_plla(&mut self) {
-        self.configure_plla(PllAClockConfig {
-            mula: 0,
-            diva: 1,
-            count: 0,
-        });
+        self.ckgr_pllar
+            .write(|w| unsafe { w.one().set_bit().mula().bits(0).diva().bits(1) });
     }
 
     /// PLLA is always "enabled" but defaults to a multiplier of zero,
     /// effectively disabling it.  The resulting clock speed is the
     /// main clock * (mula + 1)/diva.
-    pub fn configure_plla(&mut self, config: PllAClockConfig) {
+    ///
+    /// Fails if the pll doesn't lock in time.
+    pub fn configure_plla(&mut self, config: PllAClockConfig) -> Result<(), ClockError> {
         self.ckgr_pllar.write(|w| unsafe {
             w.one()
                 .set_bit()
@@ -460,19 +1460,32 @@ impl SystemClocks {
                 .bits(config.count)
         });
 
-        // Wait until pll is locked
+        // Wait until pll is locked.  A disabled pll never locks.
         // 0 = not locked, 1 = locked
-        while !self.pmc_sr.read().locka().bits() {}
+        if config.mula == 0 {
+            return Ok(());
+        }
+        self.wait_until(
+            pll_timeout_slck(config.count),
+            ClockError::PllALockTimeout,
+            |s| s.pmc_sr.read().locka().bits(),
+        )
     }
 
     /// Enable the UTMI PLL, primarily used for clocking USB.
-    pub fn enable_upll(&mut self, config: UPllClockConfig) {
+    ///
+    /// Fails if the pll doesn't lock in time.
+    pub fn enable_upll(&mut self, config: UPllClockConfig) -> Result<(), ClockError> {
         self.ckgr_uckr
             .write(|w| unsafe { w.upllen().set_bit().upllcount().bits(config.count) });
 
         // Wait until pll is locked
         // 0 = not locked, 1 = locked
-        while !self.pmc_sr.read().locku().bits() {}
+        self.wait_until(
+            pll_timeout_slck(config.count),
+            ClockError::UPllLockTimeout,
+            |s| s.pmc_sr.read().locku().bits(),
+        )
     }
 
     /// Disable the UTMI PLL, disabling the USB bus and any clocks configured
@@ -482,13 +1495,27 @@ impl SystemClocks {
     }
 
     /// Select which clock source the master clock should use, along with some
-    /// options for dividing the source clock.
+    /// options for dividing the source clock.  The flash wait states are
+    /// raised before the master clock speeds up, and lowered after it slows
+    /// down.
+    ///
+    /// Fails if the master clock doesn't report ready after the change.
     pub fn set_master_clock_source_and_prescaler(
         &mut self,
         source: ClockSource,
         prescaler: Option<ClockPrescaler>,
         pll_div2: bool,
-    ) {
+    ) -> Result<(), ClockError> {
+        let current_mck = self.get_syscore();
+        let new_mck = self.get_master_clock_rate_for(
+            source,
+            prescaler.unwrap_or_else(|| self.pmc_mckr.read().pres().variant()),
+            pll_div2,
+        );
+        // The flash must keep up with the faster of the two clocks while the
+        // switch is in progress
+        self.set_flash_wait_states_for(Hertz(core::cmp::max(current_mck.0, new_mck.0)));
+
         // For PLLs, prescaler should be applied before changing the clock source
         if source == ClockSource::PLLA_CLK || source == ClockSource::UPLL_CLK {
             if let Some(prescaler) = prescaler {
@@ -496,7 +1523,7 @@ impl SystemClocks {
 
                 // Wait for the prescaler to latch
                 // 0 = not ready, 1 = ready
-                while !self.pmc_sr.read().mckrdy().bits() {}
+                self.wait_for_master_clock()?;
             }
         }
         // For switching to PLL, we have to prime it by first setting main clock and the pll
@@ -504,12 +1531,12 @@ impl SystemClocks {
         if source == ClockSource::PLLA_CLK {
             self.pmc_mckr
                 .modify(|_, w| w.css().main_clk().plladiv2().bit(pll_div2));
-            while !self.pmc_sr.read().mckrdy().bits() {}
+            self.wait_for_master_clock()?;
         }
         if source == ClockSource::UPLL_CLK {
             self.pmc_mckr
                 .modify(|_, w| w.css().main_clk().uplldiv2().bit(pll_div2));
-            while !self.pmc_sr.read().mckrdy().bits() {}
+            self.wait_for_master_clock()?;
         }
 
         // Switch to the desired clock
@@ -526,7 +1553,7 @@ impl SystemClocks {
 
         // Wait until master clock reports ready
         // 0 = not ready, 1 = ready
-        while !self.pmc_sr.read().mckrdy().bits() {}
+        self.wait_for_master_clock()?;
 
         // For slow and main clocks, prescaler should be applied afterchanging
         // the clock source
@@ -536,114 +1563,265 @@ impl SystemClocks {
 
                 // Wait for the prescaler to latch
                 // 0 = not ready, 1 = ready
-                while !self.pmc_sr.read().mckrdy().bits() {}
+                self.wait_for_master_clock()?;
             }
         }
+
+        // Drop any wait states no longer needed at the new frequency
+        self.set_flash_wait_states_for(new_mck);
+        Ok(())
     }
 
-    /// Enable the clock for the specified peripheral.  Some peripherals'
-    /// clocks are not under PMC control - passing the ID for these clocks
-    /// will silently do nothing.
-    pub fn enable_peripheral_clock(&mut self, pid: PeripheralID) {
-        match pid {
-            PeripheralID::Id00Supc => (),  // Clock not under PMC control
-            PeripheralID::Id01Rstc => (),  // Clock not under PMC control
-            PeripheralID::Id02Rtc => (),   // Clock not under PMC control
-            PeripheralID::Id03Rtt => (),   // Clock not under PMC control
-            PeripheralID::Id04Wdg => (),   // Clock not under PMC control
-            PeripheralID::Id05Pmc => (),   // Clock not under PMC control
-            PeripheralID::Id06Eefc0 => (), // Clock not under PMC control
-            PeripheralID::Id07Eefc1 => (), // Clock not under PMC control
-            PeripheralID::Id08Uart => self.pmc_pcer0.write_with_zero(|w| w.pid8().set_bit()),
-            PeripheralID::Id09SmcSdramc => self.pmc_pcer0.write_with_zero(|w| w.pid9().set_bit()),
-            PeripheralID::Id10Sdramc => self.pmc_pcer0.write_with_zero(|w| w.pid10().set_bit()),
-            PeripheralID::Id11PioA => self.pmc_pcer0.write_with_zero(|w| w.pid11().set_bit()),
-            PeripheralID::Id12PioB => self.pmc_pcer0.write_with_zero(|w| w.pid12().set_bit()),
-            PeripheralID::Id13PioC => self.pmc_pcer0.write_with_zero(|w| w.pid13().set_bit()),
-            PeripheralID::Id14PioD => self.pmc_pcer0.write_with_zero(|w| w.pid14().set_bit()),
-            PeripheralID::Id15PioE => self.pmc_pcer0.write_with_zero(|w| w.pid15().set_bit()),
-            PeripheralID::Id16PioF => self.pmc_pcer0.write_with_zero(|w| w.pid16().set_bit()),
-            PeripheralID::Id17Usart0 => self.pmc_pcer0.write_with_zero(|w| w.pid17().set_bit()),
-            PeripheralID::Id18Usart1 => self.pmc_pcer0.write_with_zero(|w| w.pid18().set_bit()),
-            PeripheralID::Id19Usart2 => self.pmc_pcer0.write_with_zero(|w| w.pid19().set_bit()),
-            PeripheralID::Id20Usart3 => self.pmc_pcer0.write_with_zero(|w| w.pid20().set_bit()),
-            PeripheralID::Id21Hsmci => self.pmc_pcer0.write_with_zero(|w| w.pid21().set_bit()),
-            PeripheralID::Id22Twi0 => self.pmc_pcer0.write_with_zero(|w| w.pid22().set_bit()),
-            PeripheralID::Id23Twi1 => self.pmc_pcer0.write_with_zero(|w| w.pid23().set_bit()),
-            PeripheralID::Id24Spi0 => self.pmc_pcer0.write_with_zero(|w| w.pid24().set_bit()),
-            PeripheralID::Id25Spi1 => self.pmc_pcer0.write_with_zero(|w| w.pid25().set_bit()),
-            PeripheralID::Id26Ssc => self.pmc_pcer0.write_with_zero(|w| w.pid26().set_bit()),
-            PeripheralID::Id27Tc0 => self.pmc_pcer0.write_with_zero(|w| w.pid27().set_bit()),
-            PeripheralID::Id28Tc1 => self.pmc_pcer0.write_with_zero(|w| w.pid28().set_bit()),
-            PeripheralID::Id29Tc2 => self.pmc_pcer0.write_with_zero(|w| w.pid29().set_bit()),
-            PeripheralID::Id30Tc3 => self.pmc_pcer0.write_with_zero(|w| w.pid30().set_bit()),
-            PeripheralID::Id31Tc4 => self.pmc_pcer0.write_with_zero(|w| w.pid31().set_bit()),
-            PeripheralID::Id32Tc5 => self.pmc_pcer1.write_with_zero(|w| w.pid32().set_bit()),
-            PeripheralID::Id33Tc6 => self.pmc_pcer1.write_with_zero(|w| w.pid33().set_bit()),
-            PeripheralID::Id34Tc7 => self.pmc_pcer1.write_with_zero(|w| w.pid34().set_bit()),
-            PeripheralID::Id35Tc8 => self.pmc_pcer1.write_with_zero(|w| w.pid35().set_bit()),
-            PeripheralID::Id36Pwm => self.pmc_pcer1.write_with_zero(|w| w.pid36().set_bit()),
-            PeripheralID::Id37Adc => self.pmc_pcer1.write_with_zero(|w| w.pid37().set_bit()),
-            PeripheralID::Id38Dacc => self.pmc_pcer1.write_with_zero(|w| w.pid38().set_bit()),
-            PeripheralID::Id39Dmac => self.pmc_pcer1.write_with_zero(|w| w.pid39().set_bit()),
-            PeripheralID::Id40Uotghs => self.pmc_pcer1.write_with_zero(|w| w.pid40().set_bit()),
-            PeripheralID::Id41Trng => self.pmc_pcer1.write_with_zero(|w| w.pid41().set_bit()),
-            PeripheralID::Id42Emac => self.pmc_pcer1.write_with_zero(|w| w.pid42().set_bit()),
-            PeripheralID::Id43Can0 => self.pmc_pcer1.write_with_zero(|w| w.pid43().set_bit()),
-            PeripheralID::Id44Can1 => self.pmc_pcer1.write_with_zero(|w| w.pid44().set_bit()),
-        }
-    }
-
-    /// Disable the clock for the specified peripheral.  Some peripherals'
-    /// clocks are not under PMC control - passing the ID for these clocks
-    /// will silently do nothing.
-    pub fn disable_peripheral_clock(&mut self, pid: PeripheralID) {
-        match pid {
-            PeripheralID::Id00Supc => (),  // Clock not under PMC control
-            PeripheralID::Id01Rstc => (),  // Clock not under PMC control
-            PeripheralID::Id02Rtc => (),   // Clock not under PMC control
-            PeripheralID::Id03Rtt => (),   // Clock not under PMC control
-            PeripheralID::Id04Wdg => (),   // Clock not under PMC control
-            PeripheralID::Id05Pmc => (),   // Clock not under PMC control
-            PeripheralID::Id06Eefc0 => (), // Clock not under PMC control
-            PeripheralID::Id07Eefc1 => (), // Clock not under PMC control
-            PeripheralID::Id08Uart => self.pmc_pcdr0.write_with_zero(|w| w.pid8().set_bit()),
-            PeripheralID::Id09SmcSdramc => self.pmc_pcdr0.write_with_zero(|w| w.pid9().set_bit()),
-            PeripheralID::Id10Sdramc => self.pmc_pcdr0.write_with_zero(|w| w.pid10().set_bit()),
-            PeripheralID::Id11PioA => self.pmc_pcdr0.write_with_zero(|w| w.pid11().set_bit()),
-            PeripheralID::Id12PioB => self.pmc_pcdr0.write_with_zero(|w| w.pid12().set_bit()),
-            PeripheralID::Id13PioC => self.pmc_pcdr0.write_with_zero(|w| w.pid13().set_bit()),
-            PeripheralID::Id14PioD => self.pmc_pcdr0.write_with_zero(|w| w.pid14().set_bit()),
-            PeripheralID::Id15PioE => self.pmc_pcdr0.write_with_zero(|w| w.pid15().set_bit()),
-            PeripheralID::Id16PioF => self.pmc_pcdr0.write_with_zero(|w| w.pid16().set_bit()),
-            PeripheralID::Id17Usart0 => self.pmc_pcdr0.write_with_zero(|w| w.pid17().set_bit()),
-            PeripheralID::Id18Usart1 => self.pmc_pcdr0.write_with_zero(|w| w.pid18().set_bit()),
-            PeripheralID::Id19Usart2 => self.pmc_pcdr0.write_with_zero(|w| w.pid19().set_bit()),
-            PeripheralID::Id20Usart3 => self.pmc_pcdr0.write_with_zero(|w| w.pid20().set_bit()),
-            PeripheralID::Id21Hsmci => self.pmc_pcdr0.write_with_zero(|w| w.pid21().set_bit()),
-            PeripheralID::Id22Twi0 => self.pmc_pcdr0.write_with_zero(|w| w.pid22().set_bit()),
-            PeripheralID::Id23Twi1 => self.pmc_pcdr0.write_with_zero(|w| w.pid23().set_bit()),
-            PeripheralID::Id24Spi0 => self.pmc_pcdr0.write_with_zero(|w| w.pid24().set_bit()),
-            PeripheralID::Id25Spi1 => self.pmc_pcdr0.write_with_zero(|w| w.pid25().set_bit()),
-            PeripheralID::Id26Ssc => self.pmc_pcdr0.write_with_zero(|w| w.pid26().set_bit()),
-            PeripheralID::Id27Tc0 => self.pmc_pcdr0.write_with_zero(|w| w.pid27().set_bit()),
-            PeripheralID::Id28Tc1 => self.pmc_pcdr0.write_with_zero(|w| w.pid28().set_bit()),
-            PeripheralID::Id29Tc2 => self.pmc_pcdr0.write_with_zero(|w| w.pid29().set_bit()),
-            PeripheralID::Id30Tc3 => self.pmc_pcdr0.write_with_zero(|w| w.pid30().set_bit()),
-            PeripheralID::Id31Tc4 => self.pmc_pcdr0.write_with_zero(|w| w.pid31().set_bit()),
-            PeripheralID::Id32Tc5 => self.pmc_pcdr1.write_with_zero(|w| w.pid32().set_bit()),
-            PeripheralID::Id33Tc6 => self.pmc_pcdr1.write_with_zero(|w| w.pid33().set_bit()),
-            PeripheralID::Id34Tc7 => self.pmc_pcdr1.write_with_zero(|w| w.pid34().set_bit()),
-            PeripheralID::Id35Tc8 => self.pmc_pcdr1.write_with_zero(|w| w.pid35().set_bit()),
-            PeripheralID::Id36Pwm => self.pmc_pcdr1.write_with_zero(|w| w.pid36().set_bit()),
-            PeripheralID::Id37Adc => self.pmc_pcdr1.write_with_zero(|w| w.pid37().set_bit()),
-            PeripheralID::Id38Dacc => self.pmc_pcdr1.write_with_zero(|w| w.pid38().set_bit()),
-            PeripheralID::Id39Dmac => self.pmc_pcdr1.write_with_zero(|w| w.pid39().set_bit()),
-            PeripheralID::Id40Uotghs => self.pmc_pcdr1.write_with_zero(|w| w.pid40().set_bit()),
-            PeripheralID::Id41Trng => self.pmc_pcdr1.write_with_zero(|w| w.pid41().set_bit()),
-            PeripheralID::Id42Emac => self.pmc_pcdr1.write_with_zero(|w| w.pid42().set_bit()),
-            PeripheralID::Id43Can0 => self.pmc_pcdr1.write_with_zero(|w| w.pid43().set_bit()),
-            PeripheralID::Id44Can1 => self.pmc_pcdr1.write_with_zero(|w| w.pid44().set_bit()),
+    /// Wait until the master clock reports ready after a change.
+    fn wait_for_master_clock(&self) -> Result<(), ClockError> {
+        self.wait_until(CLOCK_TIMEOUT_SLCK, ClockError::MasterClockNotReady, |s| {
+            s.pmc_sr.read().mckrdy().bits()
+        })
+    }
+
+    /// Poll until `ready` returns true, giving up with `error` once the
+    /// given number of slow clock cycles has certainly passed.  The bound is
+    /// derived from the master clock frequency when the wait starts, so it
+    /// must be called after any change to the master clock settings.
+    pub(crate) fn wait_until<F>(
+        &self,
+        slck_cycles: u32,
+        error: ClockError,
+        ready: F,
+    ) -> Result<(), ClockError>
+    where
+        F: Fn(&Self) -> bool,
+    {
+        for _ in 0..timeout_iterations(slck_cycles, self.get_syscore()) {
+            if ready(self) {
+                return Ok(());
+            }
+        }
+        Err(error)
+    }
+
+    /// Enable the PMC clock for peripheral `P`, and return a token proving
+    /// that it is running.  Driver constructors take this token, so a driver
+    /// can't be created for a peripheral whose clock is off.
+    ///
+    /// Returns `None` if a token for `P` has already been handed out and
+    /// not yet given back with `disable_peripheral_clock`, or if the PMC
+    /// doesn't report the clock as running in time.
+    pub fn enable_peripheral_clock<P: PmcPeripheral>(&mut self) -> Option<PeripheralClock<P>> {
+        let mask = 1u64 << P::PID as u32;
+        if self.issued_peripheral_clocks & mask != 0 {
+            return None;
+        }
+
+        self.write_peripheral_clock_enable(P::PID);
+
+        // Wait until the PMC reports the clock as running
+        let running = (0..timeout_iterations(CLOCK_TIMEOUT_SLCK, self.get_syscore()))
+            .any(|_| self.is_peripheral_clock_enabled(P::PID));
+        if !running {
+            self.write_peripheral_clock_disable(P::PID);
+            return None;
+        }
+
+        self.issued_peripheral_clocks |= mask;
+        Some(PeripheralClock {
+            _peripheral: PhantomData,
+        })
+    }
+
+    /// Disable the PMC clock for peripheral `P`, consuming its token.
+    pub fn disable_peripheral_clock<P: PmcPeripheral>(&mut self, _clock: PeripheralClock<P>) {
+        self.write_peripheral_clock_disable(P::PID);
+        self.issued_peripheral_clocks &= !(1u64 << P::PID as u32);
+    }
+
+    /// Return whether the clock for the specified peripheral is running,
+    /// as reported by the PMC.  Peripherals 0-7 are not under PMC control,
+    /// and are always clocked.
+    pub fn is_peripheral_clock_enabled(&self, pid: PeripheralID) -> bool {
+        match pid as u32 {
+            id if id < 8 => true,
+            id if id < 32 => self.pmc_pcsr0.read().bits() & (1 << id) != 0,
+            id => self.pmc_pcsr1.read().bits() & (1 << (id - 32)) != 0,
         }
     }
+
+    /// Set the divider from the master clock to the given peripheral's
+    /// clock, leaving the clock enabled or disabled as it was.  Only the
+    /// CAN controllers honour the divider.
+    pub fn set_peripheral_clock_divider(&mut self, pid: PeripheralID, div: PeripheralClockDivider) {
+        let enabled = self.is_peripheral_clock_enabled(pid);
+        self.pmc_pcr.write(|w| unsafe {
+            w.pid()
+                .bits(pid as u8)
+                .cmd()
+                .set_bit()
+                .div()
+                .variant(div)
+                .en()
+                .bit(enabled)
+        });
+    }
+
+    /// Return the divider from the master clock to the given peripheral's
+    /// clock.
+    pub fn get_peripheral_clock_divider(&self, pid: PeripheralID) -> PeripheralClockDivider {
+        // Select the peripheral to read back, without changing anything
+        // 0 = read, 1 = write
+        self.pmc_pcr
+            .write(|w| unsafe { w.pid().bits(pid as u8).cmd().clear_bit() });
+        match self.pmc_pcr.read().div().variant() {
+            Variant::Val(div) => div,
+            Variant::Res(_) => unreachable!(),
+        }
+    }
+
+    /// Return the frequency of the clock driving the given peripheral.
+    pub fn get_peripheral_clock_rate(&self, pid: PeripheralID) -> Hertz {
+        Hertz(self.get_syscore().0 >> self.get_peripheral_clock_divider(pid) as u8)
+    }
+
+    fn write_peripheral_clock_enable(&mut self, pid: PeripheralID) {
+        match pid as u32 {
+            id if id < 8 => (), // Clock not under PMC control
+            id if id < 32 => self
+                .pmc_pcer0
+                .write_with_zero(|w| unsafe { w.bits(1 << id) }),
+            id => self
+                .pmc_pcer1
+                .write_with_zero(|w| unsafe { w.bits(1 << (id - 32)) }),
+        }
+    }
+
+    fn write_peripheral_clock_disable(&mut self, pid: PeripheralID) {
+        match pid as u32 {
+            id if id < 8 => (), // Clock not under PMC control
+            id if id < 32 => self
+                .pmc_pcdr0
+                .write_with_zero(|w| unsafe { w.bits(1 << id) }),
+            id => self
+                .pmc_pcdr1
+                .write_with_zero(|w| unsafe { w.bits(1 << (id - 32)) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::*;
+
+    fn plla_mck(mainck: Hertz, settings: &PllASettings) -> u32 {
+        let pll = mainck.0 / settings.plla.diva as u32 * (settings.plla.mula as u32 + 1);
+        let pres = match settings.prescaler {
+            CLK_3 => 3,
+            x => 1 << (x as u8),
+        };
+        pll / pres / (1 + settings.pll_div2 as u32)
+    }
+
+    #[test]
+    fn solve_plla_84mhz_from_12mhz() {
+        let settings = PllASettings::solve(MegaHertz(12).into(), MegaHertz(84).into()).unwrap();
+        assert_eq!(settings.mck, Hertz(84_000_000));
+        assert_eq!(plla_mck(MegaHertz(12).into(), &settings), 84_000_000);
+    }
+
+    #[test]
+    fn solve_plla_uses_div2_below_pll_range() {
+        let settings = PllASettings::solve(MegaHertz(12).into(), MegaHertz(48).into()).unwrap();
+        assert_eq!(settings.mck, Hertz(48_000_000));
+        assert_eq!(plla_mck(MegaHertz(12).into(), &settings), 48_000_000);
+    }
+
+    #[test]
+    fn solve_plla_84mhz_from_8mhz() {
+        let settings = PllASettings::solve(MegaHertz(8).into(), MegaHertz(84).into()).unwrap();
+        assert_eq!(settings.mck, Hertz(84_000_000));
+        assert_eq!(plla_mck(MegaHertz(8).into(), &settings), 84_000_000);
+    }
+
+    #[test]
+    fn solve_plla_reports_nearest_frequency() {
+        let settings = PllASettings::solve(MegaHertz(12).into(), MegaHertz(50).into()).unwrap();
+        assert_eq!(settings.mck, Hertz(48_000_000));
+        assert_eq!(plla_mck(MegaHertz(12).into(), &settings), 48_000_000);
+    }
+
+    #[test]
+    fn solve_plla_rejects_overclocking() {
+        assert_eq!(
+            PllASettings::solve(MegaHertz(12).into(), MegaHertz(96).into()),
+            Err(ClockError::InvalidMasterClockFrequency)
+        );
+    }
+
+    #[test]
+    fn solve_plla_rejects_slow_input() {
+        assert_eq!(
+            PllASettings::solve(MegaHertz(4).into(), MegaHertz(84).into()),
+            Err(ClockError::PllAUnattainable)
+        );
+    }
+
+    #[test]
+    fn plla_keeps_its_output_on_a_new_oscillator() {
+        // 8MHz * 21 / 2 = 84MHz, moved to a 12MHz crystal: 12MHz * 7
+        assert_eq!(
+            plla_for_output(MegaHertz(12).into(), MegaHertz(84).into(), 0x3f),
+            Some(PllAClockConfig {
+                mula: 6,
+                diva: 1,
+                count: 0x3f
+            })
+        );
+        assert_eq!(
+            plla_for_output(MegaHertz(8).into(), MegaHertz(96).into(), 0x3f),
+            Some(PllAClockConfig {
+                mula: 11,
+                diva: 1,
+                count: 0x3f
+            })
+        );
+        // 4MHz is below the PLLA input range
+        assert_eq!(
+            plla_for_output(MegaHertz(4).into(), MegaHertz(84).into(), 0x3f),
+            None
+        );
+        // 252MHz is above the PLLA output range
+        assert_eq!(
+            plla_for_output(MegaHertz(12).into(), MegaHertz(252).into(), 0x3f),
+            None
+        );
+    }
+
+    #[test]
+    fn main_xtal_check_tells_common_crystals_apart() {
+        let slck = Hertz(32768);
+        // 16 slow clock cycles of a 12MHz crystal
+        let measured = mainf_to_frequency(5859, slck);
+        assert!(main_xtal_matches(MegaHertz(12).into(), measured));
+        assert!(!main_xtal_matches(MegaHertz(8).into(), measured));
+        assert!(!main_xtal_matches(MegaHertz(16).into(), measured));
+    }
+
+    #[test]
+    fn timeout_scales_with_master_clock() {
+        // A dead crystal at boot, with MCK on the 4MHz RC, gives up after
+        // about a tenth of a second rather than tens of seconds
+        assert_eq!(
+            timeout_iterations(CLOCK_TIMEOUT_SLCK, MegaHertz(4).into()),
+            409_600
+        );
+        assert_eq!(timeout_iterations(CLOCK_TIMEOUT_SLCK, MCK_MAX), 8_601_600);
+        assert_eq!(timeout_iterations(u32::MAX, MCK_MAX), u32::MAX);
+    }
+
+    #[test]
+    #[cfg(feature = "usb")]
+    fn usb_divider_reaches_48mhz() {
+        assert_eq!(usb_divider(Hertz(480_000_000)), Some(9));
+        assert_eq!(usb_divider(Hertz(96_000_000)), Some(1));
+        assert_eq!(usb_divider(Hertz(84_000_000)), None);
+        assert_eq!(usb_divider(Hertz(960_000_000)), None);
+    }
 }
@@ -0,0 +1,181 @@
+use super::usart::usart_common;
+use crate::clock::{Clocks, PeripheralClock, PmcPeripheral};
+use crate::hal::serial;
+use crate::mux::{RxPin, TxPin};
+use crate::target_device::{USART0, USART1, USART2};
+use crate::time::Hertz;
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+use crate::target_device::USART3;
+
+/// Return the demodulator filter setting rejecting the longest pulses
+/// still shorter than the 1.41 µs minimum IrDA SIR pulse, given the
+/// peripheral clock `pclk`.
+fn irda_filter(pclk: Hertz) -> u8 {
+    // Pulses shorter than (IRDA_FILTER + 3) clock periods are rejected,
+    // which must stay below 1.41 µs
+    let periods = (pclk.0 as u64 * 141).saturating_sub(1) / 100_000_000;
+    periods.saturating_sub(3).min(0xff) as u8
+}
+
+/// A USART in IrDA SIR mode, driving an infrared transceiver.
+///
+/// The transceiver receives its own transmissions, so the receiver is
+/// disabled while transmitting.  It's enabled again, dropping the echoed
+/// data, once a flush completes or a read is attempted after the last
+/// stop bit left.
+pub struct IrdaSerial<UsartP, RX, TX> {
+    /// USART peripheral from the PAC
+    usart_p: UsartP,
+    /// Serial RX pin
+    _rx: RX,
+    /// Serial TX pin
+    _tx: TX,
+    /// Proof that the peripheral's clock is running
+    clock: PeripheralClock<UsartP>,
+    /// Baud rate last requested, kept to recompute the divider when the
+    /// clocks change
+    baud_rate: Option<Hertz>,
+    /// Whether the transmitter currently has the line
+    transmitting: bool,
+}
+
+impl<UsartP, RX, TX> core::ops::Deref for IrdaSerial<UsartP, RX, TX> {
+    type Target = UsartP;
+
+    fn deref(&self) -> &Self::Target {
+        &self.usart_p
+    }
+}
+
+impl<UsartP, RX, TX> core::ops::DerefMut for IrdaSerial<UsartP, RX, TX> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.usart_p
+    }
+}
+
+macro_rules! irda {
+    ($USART:ident, $usart:ident) => {
+        impl<RX: RxPin<$USART>, TX: TxPin<$USART>> IrdaSerial<$USART, RX, TX> {
+            /// Instantiate a representation of a USART in IrDA mode, with
+            /// its demodulator filter set from the current clocks.
+            pub fn new(
+                usart_p: $USART,
+                rx: RX,
+                tx: TX,
+                clocks: &Clocks,
+                clock: PeripheralClock<$USART>,
+            ) -> Self {
+                let mut irda = Self {
+                    usart_p,
+                    _rx: rx,
+                    _tx: tx,
+                    clock,
+                    baud_rate: None,
+                    transmitting: false,
+                };
+                irda.enable(clocks);
+                irda
+            }
+        }
+
+        impl<RX, TX> IrdaSerial<$USART, RX, TX> {
+            fn enable(&mut self, clocks: &Clocks) {
+                self.reset(crate::target_device::$usart::mr::USART_MODE_A::IRDA);
+                self.set_filter(clocks);
+                // Listen until there's something to send
+                self.cr().write_with_zero(|w| w.rxen().set_bit());
+            }
+
+            fn set_filter(&mut self, clocks: &Clocks) {
+                let filter = irda_filter(clocks.peripheral_clock($USART::PID));
+                self.if_.write(|w| unsafe { w.irda_filter().bits(filter) });
+            }
+
+            // Hand the line back to the receiver once the transmission is
+            // over, dropping what it may have caught of it
+            fn turn_around(&mut self) -> nb::Result<(), core::convert::Infallible> {
+                if self.transmitting {
+                    if !self.csr().read().txempty().bits() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+
+                    self.cr().write_with_zero(|w| {
+                        w.txdis().set_bit().rstrx().set_bit().rxen().set_bit()
+                    });
+                    self.transmitting = false;
+                }
+
+                Ok(())
+            }
+        }
+
+        usart_common!(
+            base $USART, $usart, IrdaSerial<RX, TX>,
+            { usart_p, _rx: RX, _tx: TX },
+            set_filter
+        );
+        usart_common!(line $USART, $usart, IrdaSerial<RX, TX>);
+        usart_common!(fmt $USART, IrdaSerial<RX, TX>);
+
+        impl<RX, TX> serial::Write<u8> for IrdaSerial<$USART, RX, TX> {
+            type Error = core::convert::Infallible;
+
+            fn try_write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+                if !self.transmitting {
+                    self.cr()
+                        .write_with_zero(|w| w.rxdis().set_bit().txen().set_bit());
+                    self.transmitting = true;
+                }
+
+                if !self.csr().read().txrdy().bits() {
+                    return Err(nb::Error::WouldBlock);
+                }
+
+                self.thr
+                    .write_with_zero(|w| unsafe { w.txchr().bits(word as u16) });
+
+                Ok(())
+            }
+
+            fn try_flush(&mut self) -> nb::Result<(), Self::Error> {
+                self.turn_around()
+            }
+        }
+
+        impl<RX, TX> serial::Read<u8> for IrdaSerial<$USART, RX, TX> {
+            type Error = core::convert::Infallible;
+
+            fn try_read(&mut self) -> nb::Result<u8, Self::Error> {
+                self.turn_around()?;
+
+                if !self.csr().read().rxrdy().bits() {
+                    return Err(nb::Error::WouldBlock);
+                }
+
+                Ok(self.rhr.read().rxchr().bits() as u8)
+            }
+        }
+    };
+}
+
+irda!(USART0, usart0);
+irda!(USART1, usart1);
+irda!(USART2, usart2);
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+irda!(USART3, usart3);
+
+#[cfg(test)]
+mod tests {
+    use crate::comm::irda::*;
+
+    #[test]
+    fn irda_filter_stays_below_minimum_pulse() {
+        // 84 MHz * 1.41 µs = 118.4 periods, so IRDA_FILTER + 3 <= 118
+        assert_eq!(irda_filter(Hertz(84_000_000)), 115);
+        assert_eq!(irda_filter(Hertz(12_000_000)), 13);
+        assert_eq!(irda_filter(Hertz(1_000_000)), 0);
+        assert_eq!(irda_filter(Hertz(400_000_000)), 0xff);
+    }
+}
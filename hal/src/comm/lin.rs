@@ -0,0 +1,374 @@
+use super::usart::{usart_baud_rate, usart_common};
+use super::{Master, Slave};
+use crate::clock::{Clocks, PeripheralClock, PmcPeripheral};
+use crate::mux::{RxPin, TxPin};
+use crate::target_device::usart0::csr_lin_mode;
+use crate::target_device::usart0::linmr::NACT_A;
+use crate::target_device::usart0::mr::USART_MODE_A;
+use crate::target_device::USART0;
+use crate::time::Hertz;
+use core::marker::PhantomData;
+
+/// Checksum appended to the response of a LIN frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    /// LIN 1.3 checksum, over the data bytes only
+    Classic,
+    /// LIN 2.0 checksum, over the protected identifier and the data bytes
+    Enhanced,
+}
+
+/// Errors reported by a LIN frame transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinError {
+    /// A received byte was lost because the previous one wasn't read in
+    /// time
+    Overrun,
+    /// A byte was received without a valid stop bit
+    Framing,
+    /// The bus didn't carry the bit being transmitted
+    Bit,
+    /// The synchronization field received in the header was out of
+    /// tolerance
+    InconsistentSync,
+    /// The parity bits of the received identifier were wrong
+    IdentifierParity,
+    /// The checksum of the received response was wrong
+    Checksum,
+    /// No slave answered a subscribed frame in time
+    SlaveNotResponding,
+    /// The response to send or receive wasn't 1 to 256 bytes long
+    InvalidLength,
+    /// The frame didn't complete within the longest frame time the bus
+    /// allows, and was aborted
+    Timeout,
+}
+
+/// Return the first error flagged in the channel status `csr`.
+fn lin_error(csr: &csr_lin_mode::R) -> Option<LinError> {
+    [
+        (csr.linsnre().bit(), LinError::SlaveNotResponding),
+        (csr.linisfe().bit(), LinError::InconsistentSync),
+        (csr.linipe().bit(), LinError::IdentifierParity),
+        (csr.linbe().bit(), LinError::Bit),
+        (csr.lince().bit(), LinError::Checksum),
+        (csr.frame().bit(), LinError::Framing),
+        (csr.ovre().bit(), LinError::Overrun),
+    ]
+    .iter()
+    .find(|(flagged, _)| *flagged)
+    .map(|&(_, error)| error)
+}
+
+// Bit times of the longest frame: a 34-bit header and a response of 256
+// data bytes and the checksum, 10 bits each, stretched by the 40% the LIN
+// standard allows
+const FRAME_BITS_MAX: u32 = (34 + 10 * 257) * 14 / 10;
+
+/// Return how many times the channel status may be polled to wait out the
+/// longest frame, at the bit rate of divider `cd` and fractional part
+/// `fp`.  Each poll takes at least one peripheral clock cycle, and a bit
+/// lasts 16 * (`cd` + `fp` / 8) of them.
+fn frame_timeout_polls(cd: u16, fp: u8) -> u32 {
+    FRAME_BITS_MAX * (16 * cd as u32 + 2 * fp as u32)
+}
+
+/// A USART in LIN mode.
+///
+/// The USART handles the frame format: as `Master`, writing an identifier
+/// sends the break, the synchronization field and the protected
+/// identifier; as `Slave`, it detects the break, resynchronizes on the
+/// synchronization field and checks the identifier parity.  In both
+/// roles it computes and checks the response checksum.
+///
+/// Frame transfers block until the frame completes or fails.  A transfer
+/// still waiting once the longest frame allowed at the configured baud
+/// rate has gone by, such as a slave waiting on a master that went silent,
+/// is aborted with `LinError::Timeout`.
+pub struct Lin<UsartP, ROLE, RX, TX> {
+    /// USART peripheral from the PAC
+    usart_p: UsartP,
+    /// Serial RX pin
+    _rx: RX,
+    /// Serial TX pin
+    _tx: TX,
+    /// Proof that the peripheral's clock is running
+    clock: PeripheralClock<UsartP>,
+    /// Baud rate last requested, kept to recompute the divider when the
+    /// clocks change
+    baud_rate: Option<Hertz>,
+    _role: PhantomData<ROLE>,
+}
+
+impl<UsartP, ROLE, RX, TX> core::ops::Deref for Lin<UsartP, ROLE, RX, TX> {
+    type Target = UsartP;
+
+    fn deref(&self) -> &Self::Target {
+        &self.usart_p
+    }
+}
+
+impl<UsartP, ROLE, RX, TX> core::ops::DerefMut for Lin<UsartP, ROLE, RX, TX> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.usart_p
+    }
+}
+
+impl<RX: RxPin<USART0>, TX: TxPin<USART0>> Lin<USART0, Master, RX, TX> {
+    /// Instantiate a representation of USART0 as a LIN master node, using
+    /// enhanced checksums.
+    pub fn new_master<I: Into<Hertz>>(
+        usart_p: USART0,
+        rx: RX,
+        tx: TX,
+        baud_rate: I,
+        clocks: &Clocks,
+        clock: PeripheralClock<USART0>,
+    ) -> Self {
+        let mut lin = Self {
+            usart_p,
+            _rx: rx,
+            _tx: tx,
+            clock,
+            baud_rate: None,
+            _role: PhantomData,
+        };
+        lin.enable(true, baud_rate.into(), clocks);
+        lin
+    }
+}
+
+impl<RX, TX> Lin<USART0, Master, RX, TX> {
+    /// Send the header of frame `id`, then `data` as its response.  `data`
+    /// must hold 1 to 256 bytes.
+    pub fn publish(&mut self, id: u8, data: &[u8]) -> Result<(), LinError> {
+        self.set_response(NACT_A::PUBLISH, data.len())?;
+        self.send_header(id);
+        self.send_response(data)
+    }
+
+    /// Send the header of frame `id`, and receive its response from a
+    /// slave into `buffer`.  `buffer` must hold 1 to 256 bytes.
+    pub fn subscribe(&mut self, id: u8, buffer: &mut [u8]) -> Result<(), LinError> {
+        self.set_response(NACT_A::SUBSCRIBE, buffer.len())?;
+        self.send_header(id);
+        self.receive_response(buffer)
+    }
+
+    /// Send the header of frame `id`, whose response is exchanged between
+    /// slaves.
+    pub fn send_header_only(&mut self, id: u8) -> Result<(), LinError> {
+        self.linmr.modify(|_, w| w.nact().ignore());
+        self.send_header(id);
+        self.wait_for(|csr| csr.linid().bit())
+    }
+
+    /// Return the bus baud rate, calculated to be a fraction of the
+    /// peripheral clock speed.
+    pub fn get_baudrate(&self, clocks: &Clocks) -> Hertz {
+        let brgr = self.brgr.read();
+        usart_baud_rate(
+            clocks.peripheral_clock(USART0::PID),
+            brgr.cd().bits(),
+            brgr.fp().bits(),
+        )
+    }
+
+    fn send_header(&mut self, id: u8) {
+        self.cr().write_with_zero(|w| w.rststa().set_bit());
+        self.linir.write(|w| unsafe { w.idchr().bits(id & 0x3f) });
+    }
+}
+
+impl<RX: RxPin<USART0>, TX: TxPin<USART0>> Lin<USART0, Slave, RX, TX> {
+    /// Instantiate a representation of USART0 as a LIN slave node at the
+    /// nominal `baud_rate`, using enhanced checksums.  The USART
+    /// resynchronizes on each header.
+    pub fn new_slave<I: Into<Hertz>>(
+        usart_p: USART0,
+        rx: RX,
+        tx: TX,
+        baud_rate: I,
+        clocks: &Clocks,
+        clock: PeripheralClock<USART0>,
+    ) -> Self {
+        let mut lin = Self {
+            usart_p,
+            _rx: rx,
+            _tx: tx,
+            clock,
+            baud_rate: None,
+            _role: PhantomData,
+        };
+        lin.enable(false, baud_rate.into(), clocks);
+        lin
+    }
+}
+
+impl<RX, TX> Lin<USART0, Slave, RX, TX> {
+    /// Return the identifier of the next frame header, once received.
+    /// The response must then be handled with `respond()`, `receive()` or
+    /// `ignore()`.
+    pub fn try_read_header(&mut self) -> nb::Result<u8, LinError> {
+        if !self.status()?.linid().bit() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.cr().write_with_zero(|w| w.rststa().set_bit());
+        Ok(self.linir.read().idchr().bits() & 0x3f)
+    }
+
+    /// Send `data` as the response to the last header.  `data` must hold
+    /// 1 to 256 bytes.
+    pub fn respond(&mut self, data: &[u8]) -> Result<(), LinError> {
+        self.set_response(NACT_A::PUBLISH, data.len())?;
+        self.send_response(data)
+    }
+
+    /// Receive the response to the last header into `buffer`.  `buffer`
+    /// must hold 1 to 256 bytes.
+    pub fn receive(&mut self, buffer: &mut [u8]) -> Result<(), LinError> {
+        self.set_response(NACT_A::SUBSCRIBE, buffer.len())?;
+        self.receive_response(buffer)
+    }
+
+    /// Let the response to the last header go by.
+    pub fn ignore(&mut self) {
+        self.linmr.modify(|_, w| w.nact().ignore());
+    }
+
+    /// Return the bus baud rate measured on the last synchronization
+    /// field, calculated to be a fraction of the peripheral clock speed.
+    pub fn get_baudrate(&self, clocks: &Clocks) -> Hertz {
+        let linbrr = self.linbrr.read();
+        usart_baud_rate(
+            clocks.peripheral_clock(USART0::PID),
+            linbrr.lincd().bits(),
+            linbrr.linfp().bits(),
+        )
+    }
+}
+
+impl<ROLE, RX, TX> Lin<USART0, ROLE, RX, TX> {
+    fn enable(&mut self, master: bool, baud_rate: Hertz, clocks: &Clocks) {
+        self.reset(if master {
+            USART_MODE_A::LIN_MASTER
+        } else {
+            USART_MODE_A::LIN_SLAVE
+        });
+        self.linmr.write(|w| w.nact().ignore());
+        self.set_baudrate(baud_rate, clocks);
+        self.cr()
+            .write_with_zero(|w| w.rxen().set_bit().txen().set_bit());
+    }
+
+    /// Set the checksum appended to, and checked on, frame responses.
+    pub fn set_checksum(&mut self, checksum: Checksum) {
+        self.linmr
+            .modify(|_, w| w.chktyp().bit(checksum == Checksum::Classic));
+    }
+
+    /// Get the checksum appended to, and checked on, frame responses.
+    pub fn get_checksum(&self) -> Checksum {
+        if self.linmr.read().chktyp().bits() {
+            Checksum::Classic
+        } else {
+            Checksum::Enhanced
+        }
+    }
+
+    /// Abort the frame in progress.
+    pub fn abort(&mut self) {
+        self.cr().write_with_zero(|w| w.linabt().set_bit());
+    }
+
+    /// Send a wakeup signal, to bring the bus out of sleep.
+    pub fn wake_up(&mut self) {
+        self.cr().write_with_zero(|w| w.linwkup().set_bit());
+    }
+
+    fn set_response(&mut self, action: NACT_A, len: usize) -> Result<(), LinError> {
+        if !(1..=256).contains(&len) {
+            return Err(LinError::InvalidLength);
+        }
+
+        self.linmr.modify(|_, w| unsafe {
+            // DLC is the number of data bytes minus one
+            w.nact().variant(action).dlc().bits((len - 1) as u8)
+        });
+        Ok(())
+    }
+
+    // Read the channel status, reporting and clearing errors
+    fn status(&mut self) -> Result<csr_lin_mode::R, LinError> {
+        let csr = self.csr_lin_mode().read();
+        match lin_error(&csr) {
+            Some(error) => {
+                self.cr().write_with_zero(|w| w.rststa().set_bit());
+                Err(error)
+            }
+            None => Ok(csr),
+        }
+    }
+
+    // Poll the channel status until `ready`, aborting the frame if the
+    // longest frame goes by first.  The nominal rate in BRGR bounds the
+    // wait for a slave too, the 40% allowance covering its resynchronization.
+    fn wait_for<F>(&mut self, ready: F) -> Result<(), LinError>
+    where
+        F: Fn(&csr_lin_mode::R) -> bool,
+    {
+        let brgr = self.brgr.read();
+        for _ in 0..frame_timeout_polls(brgr.cd().bits(), brgr.fp().bits()) {
+            if ready(&self.status()?) {
+                return Ok(());
+            }
+        }
+        self.abort();
+        self.cr().write_with_zero(|w| w.rststa().set_bit());
+        Err(LinError::Timeout)
+    }
+
+    fn send_response(&mut self, data: &[u8]) -> Result<(), LinError> {
+        for &byte in data {
+            self.wait_for(|csr| csr.txrdy().bit())?;
+            self.thr
+                .write_with_zero(|w| unsafe { w.txchr().bits(byte as u16) });
+        }
+        self.wait_for(|csr| csr.lintc().bit())?;
+        self.cr().write_with_zero(|w| w.rststa().set_bit());
+        Ok(())
+    }
+
+    fn receive_response(&mut self, buffer: &mut [u8]) -> Result<(), LinError> {
+        for byte in buffer.iter_mut() {
+            self.wait_for(|csr| csr.rxrdy().bit())?;
+            *byte = self.rhr.read().rxchr().bits() as u8;
+        }
+        // The checksum is checked once the transfer completes
+        self.wait_for(|csr| csr.lintc().bit())?;
+        self.cr().write_with_zero(|w| w.rststa().set_bit());
+        Ok(())
+    }
+}
+
+// A master sends, and a slave expects, the rate set with `set_baudrate()`
+usart_common!(
+    base USART0, usart0, Lin<ROLE, RX, TX>,
+    { usart_p, _rx: RX, _tx: TX }
+);
+
+#[cfg(test)]
+mod tests {
+    use crate::comm::lin::*;
+
+    #[test]
+    fn frame_timeout_covers_longest_frame() {
+        // 3645 bit times of 16 * 45.5 cycles, at 115 kBd from 84 MHz
+        assert_eq!(frame_timeout_polls(45, 4), 3645 * 728);
+        // 19.2 kBd from 84 MHz
+        assert_eq!(frame_timeout_polls(273, 3), 3645 * 4374);
+        // The slowest rate still fits
+        assert_eq!(frame_timeout_polls(0xffff, 7), 3645 * 1_048_574);
+    }
+}
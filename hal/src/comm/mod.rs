@@ -7,6 +7,7 @@
 //! difficult to misuse.
 // SPI Flr|  MOSI  |  MISO  | Clock  |        |   NSS  |
 // SPI Ldr|  MISO  |  MOSI  | Clock  |   NSS  |        |
+// RS-485 |   RX   |   TX   |        |   DE   |        |
 // RS-232 |   RX   |   TX   |        |   RTS  |   CTS  | Periph ID |
 // -------+--------+--------+--------+--------+--------+-----------|
 // UART   | PA08/A | PA09/A |        |        |        |     8     |
@@ -14,15 +15,26 @@
 // USART1 | PA12/A | PA13/A | PA16/A | PA14/A | PA15/A |    18     |
 // USART2 | PB21/A | PB20/A | PB24/A | PB22/A | PB23/A |    19     |
 // USART3 | PD05/B | PD04/B | PE16/B | PF05/A | PF04/A |    20     |
+//
+// USART3 needs atsam3_e or atsam3x8h, and its clock and handshake lines
+// are only bonded out on atsam3x8h.
 
+mod irda;
+mod lin;
+mod rs485;
+mod spi;
 mod uart;
-// mod rs485;
-// mod lin;
-// mod spi;
-// mod irda;
+mod usart;
 
+pub use self::irda::*;
+pub use self::lin::*;
+pub use self::rs485::*;
+pub use self::spi::*;
 pub use self::uart::*;
-// pub use self::rs485::*;
-// pub use self::lin::*;
-// pub use self::spi::*;
-// pub use self::irda::*;
+pub use self::usart::*;
+
+/// Type state of a USART driving the bus: the SPI clock, or the LIN
+/// frame headers.
+pub struct Master;
+/// Type state of a USART following a remote master.
+pub struct Slave;
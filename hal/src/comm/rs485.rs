@@ -0,0 +1,116 @@
+use super::usart::usart_common;
+use crate::clock::PeripheralClock;
+use crate::mux::{RtsPin, RxPin, TxPin};
+use crate::target_device::{USART0, USART1, USART2};
+use crate::time::Hertz;
+
+#[cfg(feature = "sam3x8h")]
+use crate::target_device::USART3;
+
+/// A USART in RS-485 mode, driving a half-duplex transceiver.
+///
+/// The USART raises its RTS pin, wired to the transceiver's driver enable
+/// (DE), as soon as a character is written, and only drops it once the
+/// last stop bit has left the shift register and the time guard, if any,
+/// has elapsed.  The bus is released without any help from software.
+pub struct Rs485<UsartP, RX, TX, DE> {
+    /// USART peripheral from the PAC
+    usart_p: UsartP,
+    /// Serial RX pin
+    _rx: RX,
+    /// Serial TX pin
+    _tx: TX,
+    /// Request-to-send pin, driving the transceiver's driver enable
+    _de: DE,
+    /// Proof that the peripheral's clock is running
+    clock: PeripheralClock<UsartP>,
+    /// Baud rate last requested, kept to recompute the divider when the
+    /// clocks change
+    baud_rate: Option<Hertz>,
+}
+
+impl<UsartP, RX, TX, DE> core::ops::Deref for Rs485<UsartP, RX, TX, DE> {
+    type Target = UsartP;
+
+    fn deref(&self) -> &Self::Target {
+        &self.usart_p
+    }
+}
+
+impl<UsartP, RX, TX, DE> core::ops::DerefMut for Rs485<UsartP, RX, TX, DE> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.usart_p
+    }
+}
+
+macro_rules! rs485 {
+    ($USART:ident, $usart:ident) => {
+        impl<RX, TX, DE> Rs485<$USART, RX, TX, DE>
+        where
+            RX: RxPin<$USART>,
+            TX: TxPin<$USART>,
+            DE: RtsPin<$USART>,
+        {
+            /// Instantiate a representation of a USART in RS-485 mode,
+            /// with `de` driving the transceiver's driver enable.
+            pub fn new(
+                usart_p: $USART,
+                rx: RX,
+                tx: TX,
+                de: DE,
+                clock: PeripheralClock<$USART>,
+            ) -> Self {
+                let rs485 = Self {
+                    usart_p,
+                    _rx: rx,
+                    _tx: tx,
+                    _de: de,
+                    clock,
+                    baud_rate: None,
+                };
+                rs485.enable();
+                rs485
+            }
+        }
+
+        impl<RX, TX, DE> Rs485<$USART, RX, TX, DE> {
+            fn enable(&self) {
+                self.reset(crate::target_device::$usart::mr::USART_MODE_A::RS485);
+                self.cr()
+                    .write_with_zero(|w| w.rxen().set_bit().txen().set_bit());
+            }
+
+            /// Set the time guard, in bit periods, kept idle after each
+            /// character.  The driver stays enabled through the time guard
+            /// following the last character, which holds the bus in its
+            /// idle state for slow transceivers.  Zero disables it.
+            pub fn set_time_guard(&mut self, bit_periods: u8) {
+                self.ttgr.write(|w| unsafe { w.tg().bits(bit_periods) });
+            }
+
+            /// Get the time guard, in bit periods.
+            pub fn get_time_guard(&self) -> u8 {
+                self.ttgr.read().tg().bits()
+            }
+        }
+
+        usart_common!(
+            base $USART, $usart, Rs485<RX, TX, DE>,
+            { usart_p, _rx: RX, _tx: TX, _de: DE }
+        );
+        usart_common!(line $USART, $usart, Rs485<RX, TX, DE>);
+        // Flushing completes once the last stop bit and the time guard are
+        // out, when the driver enable has been released; a reply can be
+        // awaited from then on
+        usart_common!(serial $USART, Rs485<RX, TX, DE>);
+        usart_common!(fmt $USART, Rs485<RX, TX, DE>);
+    };
+}
+
+rs485!(USART0, usart0);
+rs485!(USART1, usart1);
+rs485!(USART2, usart2);
+
+// USART3's RTS line is only bonded out on the 217-pin package
+#[cfg(feature = "sam3x8h")]
+rs485!(USART3, usart3);
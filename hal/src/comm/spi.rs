@@ -0,0 +1,366 @@
+use super::{Master, Slave};
+use crate::clock::{ClockDependent, Clocks, PeripheralClock, PmcPeripheral};
+use crate::hal::blocking;
+use crate::hal::spi::{self, Mode, Phase, Polarity};
+use crate::mux::{CtsPin, RxPin, SckPin, TxPin};
+use crate::target_device::{USART0, USART1, USART2};
+use crate::time::Hertz;
+use core::marker::PhantomData;
+
+#[cfg(feature = "sam3x8h")]
+use crate::target_device::USART3;
+
+/// Number of data bits in each SPI frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameSize {
+    /// 8-bit frames, exchanged as `u8` or as the low bits of `u16` words
+    Bits8,
+    /// 9-bit frames, exchanged as the low bits of `u16` words
+    Bits9,
+}
+
+/// Errors reported by a `UsartSpi` transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpiError {
+    /// A received frame was lost because the previous one wasn't read in
+    /// time
+    Overrun,
+    /// The remote master clocked a frame out of the slave before one was
+    /// written
+    Underrun,
+}
+
+/// A USART in SPI mode.
+///
+/// In `Master` mode, the USART baud rate generator drives SCK, RX carries
+/// MISO and TX carries MOSI.  The chip select is left to a GPIO, or to the
+/// RTS pin muxed separately, which the USART drives low during transfers.
+/// In `Slave` mode, SCK is an input, RX carries MOSI, TX carries MISO,
+/// and CTS is the active-low slave select.
+pub struct UsartSpi<UsartP, ROLE, RX, TX, SCK, NSS = ()> {
+    /// USART peripheral from the PAC
+    usart_p: UsartP,
+    /// Serial RX pin
+    _rx: RX,
+    /// Serial TX pin
+    _tx: TX,
+    /// Serial clock pin
+    _sck: SCK,
+    /// Slave select pin, in slave mode
+    _nss: NSS,
+    /// Proof that the peripheral's clock is running
+    clock: PeripheralClock<UsartP>,
+    /// Clock frequency last requested, kept to recompute the divider when
+    /// the clocks change
+    frequency: Option<Hertz>,
+    _role: PhantomData<ROLE>,
+}
+
+impl<UsartP, ROLE, RX, TX, SCK, NSS> core::ops::Deref for UsartSpi<UsartP, ROLE, RX, TX, SCK, NSS> {
+    type Target = UsartP;
+
+    fn deref(&self) -> &Self::Target {
+        &self.usart_p
+    }
+}
+
+impl<UsartP, ROLE, RX, TX, SCK, NSS> core::ops::DerefMut
+    for UsartSpi<UsartP, ROLE, RX, TX, SCK, NSS>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.usart_p
+    }
+}
+
+// In SPI master mode, SCK = pclk / CD, and CD can't be lower than 6
+const SPI_MIN_DIVIDER: u32 = 6;
+
+/// Return the clock divider (CD) giving the SCK frequency closest to, and
+/// no higher than, `frequency`, from the peripheral clock `pclk`.  SCK
+/// can't run slower than `pclk / 65535`: lower frequencies, zero included,
+/// get that floor instead.
+fn spi_divider(pclk: Hertz, frequency: Hertz) -> u16 {
+    if frequency.0 == 0 {
+        return 0xffff;
+    }
+    pclk.0.div_ceil(frequency.0).clamp(SPI_MIN_DIVIDER, 0xffff) as u16
+}
+
+macro_rules! usart_spi {
+    ($USART:ident) => {
+        impl<RX, TX, SCK> UsartSpi<$USART, Master, RX, TX, SCK, ()>
+        where
+            RX: RxPin<$USART>,
+            TX: TxPin<$USART>,
+            SCK: SckPin<$USART>,
+        {
+            /// Instantiate a representation of a USART as an SPI master,
+            /// clocking SCK at `frequency` or slightly below in the given
+            /// clock `mode`, with 8-bit frames.
+            #[allow(clippy::too_many_arguments)]
+            pub fn new_master<I: Into<Hertz>>(
+                usart_p: $USART,
+                miso: RX,
+                mosi: TX,
+                sck: SCK,
+                mode: Mode,
+                frequency: I,
+                clocks: &Clocks,
+                clock: PeripheralClock<$USART>,
+            ) -> Self {
+                let mut spi = Self {
+                    usart_p,
+                    _rx: miso,
+                    _tx: mosi,
+                    _sck: sck,
+                    _nss: (),
+                    clock,
+                    frequency: None,
+                    _role: PhantomData,
+                };
+                spi.enable(true, mode);
+                spi.set_frequency(frequency, clocks);
+                spi
+            }
+        }
+
+        impl<RX, TX, SCK> UsartSpi<$USART, Master, RX, TX, SCK, ()> {
+            /// Set the SCK frequency, which is configured to be a fraction
+            /// of the peripheral clock speed.  SCK runs at the highest
+            /// frequency no higher than requested, within `pclk / 65535`
+            /// to `pclk / 6`.
+            pub fn set_frequency<I: Into<Hertz>>(&mut self, frequency: I, clocks: &Clocks) {
+                let frequency = frequency.into();
+                self.frequency = Some(frequency);
+                let cd = spi_divider(clocks.peripheral_clock($USART::PID), frequency);
+                self.brgr.write(|w| unsafe { w.cd().bits(cd) });
+            }
+
+            /// Return the SCK frequency, calculated to be a fraction of the
+            /// peripheral clock speed.
+            pub fn get_frequency(&self, clocks: &Clocks) -> Hertz {
+                let cd = self.brgr.read().cd().bits() as u32;
+                Hertz(clocks.peripheral_clock($USART::PID).0 / cd.max(1))
+            }
+        }
+
+        impl<RX, TX, SCK> ClockDependent for UsartSpi<$USART, Master, RX, TX, SCK, ()> {
+            fn clocks_changed(&mut self, clocks: &Clocks) {
+                if let Some(frequency) = self.frequency {
+                    self.set_frequency(frequency, clocks);
+                }
+            }
+        }
+
+        impl<RX, TX, SCK, NSS> UsartSpi<$USART, Slave, RX, TX, SCK, NSS>
+        where
+            RX: RxPin<$USART>,
+            TX: TxPin<$USART>,
+            SCK: SckPin<$USART>,
+            NSS: CtsPin<$USART>,
+        {
+            /// Instantiate a representation of a USART as an SPI slave in
+            /// the given clock `mode`, with 8-bit frames.
+            pub fn new_slave(
+                usart_p: $USART,
+                mosi: RX,
+                miso: TX,
+                sck: SCK,
+                nss: NSS,
+                mode: Mode,
+                clock: PeripheralClock<$USART>,
+            ) -> Self {
+                let spi = Self {
+                    usart_p,
+                    _rx: mosi,
+                    _tx: miso,
+                    _sck: sck,
+                    _nss: nss,
+                    clock,
+                    frequency: None,
+                    _role: PhantomData,
+                };
+                spi.enable(false, mode);
+                spi
+            }
+        }
+
+        impl<ROLE, RX, TX, SCK, NSS> UsartSpi<$USART, ROLE, RX, TX, SCK, NSS> {
+            fn enable(&self, master: bool, mode: Mode) {
+                self.cr_spi_mode().write_with_zero(|w| {
+                    w.rstrx()
+                        .set_bit()
+                        .rsttx()
+                        .set_bit()
+                        .rxdis()
+                        .set_bit()
+                        .txdis()
+                        .set_bit()
+                        .rststa()
+                        .set_bit()
+                });
+                self.mr_spi_mode().write_with_zero(|w| {
+                    let w = if master {
+                        // Hold transfers until the previous frame is read,
+                        // so that the master can't overrun
+                        w.usart_mode().spi_master().usclks().mck().wrdbt().set_bit()
+                    } else {
+                        w.usart_mode().spi_slave().usclks().sck()
+                    };
+                    w.chrl()
+                        ._8_bit()
+                        .cpol()
+                        .bit(mode.polarity == Polarity::IdleHigh)
+                        // CPHA set captures on the leading edge
+                        .cpha()
+                        .bit(mode.phase == Phase::CaptureOnFirstTransition)
+                });
+                if master {
+                    self.mr().modify(|_, w| w.clko().set_bit());
+                }
+                self.cr_spi_mode()
+                    .write_with_zero(|w| w.rxen().set_bit().txen().set_bit());
+            }
+
+            /// Stop the USART, and release the peripheral, its pins and its
+            /// clock token.  The clock can then be disabled with
+            /// `SystemClocks::disable_peripheral_clock`.
+            pub fn free(self) -> ($USART, RX, TX, SCK, NSS, PeripheralClock<$USART>) {
+                self.cr_spi_mode()
+                    .write_with_zero(|w| w.rxdis().set_bit().txdis().set_bit());
+                (
+                    self.usart_p,
+                    self._rx,
+                    self._tx,
+                    self._sck,
+                    self._nss,
+                    self.clock,
+                )
+            }
+
+            /// Set the number of data bits in each frame.
+            pub fn set_frame_size(&mut self, frame_size: FrameSize) {
+                self.mr()
+                    .modify(|_, w| w.mode9().bit(frame_size == FrameSize::Bits9));
+            }
+
+            /// Get the number of data bits in each frame.
+            pub fn get_frame_size(&self) -> FrameSize {
+                if self.mr().read().mode9().bits() {
+                    FrameSize::Bits9
+                } else {
+                    FrameSize::Bits8
+                }
+            }
+
+            fn read_word(&mut self) -> nb::Result<u16, SpiError> {
+                let csr = self.csr_spi_mode().read();
+                if csr.ovre().bits() {
+                    self.cr_spi_mode().write_with_zero(|w| w.rststa().set_bit());
+                    return Err(nb::Error::Other(SpiError::Overrun));
+                }
+                if csr.unre().bits() {
+                    self.cr_spi_mode().write_with_zero(|w| w.rststa().set_bit());
+                    return Err(nb::Error::Other(SpiError::Underrun));
+                }
+                if !csr.rxrdy().bits() {
+                    return Err(nb::Error::WouldBlock);
+                }
+
+                Ok(self.rhr.read().rxchr().bits())
+            }
+
+            fn send_word(&mut self, word: u16) -> nb::Result<(), SpiError> {
+                if !self.csr_spi_mode().read().txrdy().bits() {
+                    return Err(nb::Error::WouldBlock);
+                }
+
+                self.thr
+                    .write_with_zero(|w| unsafe { w.txchr().bits(word) });
+
+                Ok(())
+            }
+        }
+
+        impl<ROLE, RX, TX, SCK, NSS> spi::FullDuplex<u8>
+            for UsartSpi<$USART, ROLE, RX, TX, SCK, NSS>
+        {
+            type Error = SpiError;
+
+            fn try_read(&mut self) -> nb::Result<u8, Self::Error> {
+                self.read_word().map(|word| word as u8)
+            }
+
+            fn try_send(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+                self.send_word(word as u16)
+            }
+        }
+
+        impl<ROLE, RX, TX, SCK, NSS> spi::FullDuplex<u16>
+            for UsartSpi<$USART, ROLE, RX, TX, SCK, NSS>
+        {
+            type Error = SpiError;
+
+            fn try_read(&mut self) -> nb::Result<u16, Self::Error> {
+                self.read_word()
+            }
+
+            fn try_send(&mut self, word: u16) -> nb::Result<(), Self::Error> {
+                self.send_word(word)
+            }
+        }
+
+        impl<ROLE, RX, TX, SCK, NSS> blocking::spi::transfer::Default<u8>
+            for UsartSpi<$USART, ROLE, RX, TX, SCK, NSS>
+        {
+        }
+
+        impl<ROLE, RX, TX, SCK, NSS> blocking::spi::write::Default<u8>
+            for UsartSpi<$USART, ROLE, RX, TX, SCK, NSS>
+        {
+        }
+
+        impl<ROLE, RX, TX, SCK, NSS> blocking::spi::transfer::Default<u16>
+            for UsartSpi<$USART, ROLE, RX, TX, SCK, NSS>
+        {
+        }
+
+        impl<ROLE, RX, TX, SCK, NSS> blocking::spi::write::Default<u16>
+            for UsartSpi<$USART, ROLE, RX, TX, SCK, NSS>
+        {
+        }
+    };
+}
+
+usart_spi!(USART0);
+usart_spi!(USART1);
+usart_spi!(USART2);
+
+// USART3's clock line is only bonded out on the 217-pin package
+#[cfg(feature = "sam3x8h")]
+usart_spi!(USART3);
+
+#[cfg(test)]
+mod tests {
+    use crate::comm::spi::*;
+
+    #[test]
+    fn spi_divider_rounds_frequency_down() {
+        let mck = Hertz(84_000_000);
+        assert_eq!(spi_divider(mck, Hertz(1_000_000)), 84);
+        // 84 MHz / 5 MHz = 16.8, so CD = 17 for 4.94 MHz
+        assert_eq!(spi_divider(mck, Hertz(5_000_000)), 17);
+        assert_eq!(spi_divider(mck, Hertz(42_000_000)), 6);
+    }
+
+    #[test]
+    fn spi_divider_floors_low_frequencies() {
+        let mck = Hertz(84_000_000);
+        // 84 MHz / 65535 = 1.28 kHz is as slow as SCK goes
+        assert_eq!(spi_divider(mck, Hertz(1_282)), 65_523);
+        assert_eq!(spi_divider(mck, Hertz(1_281)), 0xffff);
+        assert_eq!(spi_divider(mck, Hertz(1_000)), 0xffff);
+        assert_eq!(spi_divider(mck, Hertz(0)), 0xffff);
+        assert_eq!(spi_divider(Hertz(u32::MAX), Hertz(u32::MAX)), 6);
+    }
+}
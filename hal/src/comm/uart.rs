@@ -1,9 +1,11 @@
+use crate::clock::{ClockDependent, Clocks, PeripheralClock, PmcPeripheral};
 use crate::hal::blocking;
 use crate::hal::serial;
 use crate::target_device::UART;
 use embedded_hal::prelude::*;
 //use crate::target_device::{UART, USART0, USART1, USART2, USART3};
 use crate::gpio::{Pa8, Pa9, PfA};
+use crate::mux::{RxPin, TxPin};
 use crate::target_device::uart::mr::{CHMODE_A, PAR_A};
 use crate::time::Hertz;
 use core::fmt;
@@ -11,15 +13,22 @@ use core::fmt;
 /// UART controller configuration
 pub struct Uart<UartP, RX, TX, RTS, CTS> {
     /// U(S)ART peripheral from the PAC
-    uart_p: UartP,
+    pub(super) uart_p: UartP,
     /// Serial RX pin
-    _rx: RX,
+    pub(super) _rx: RX,
     /// Serial TX pin
-    _tx: TX,
+    pub(super) _tx: TX,
     /// Serial Request-to-send pin, if any
-    _rts: RTS,
+    pub(super) _rts: RTS,
     /// Serial Clear-to-send pin, if any
-    _cts: CTS,
+    pub(super) _cts: CTS,
+    /// Whether the RTS and CTS lines are handshaking
+    pub(super) flow_control: bool,
+    /// Proof that the peripheral's clock is running
+    pub(super) clock: PeripheralClock<UartP>,
+    /// Baud rate last requested, kept to recompute the divider when the
+    /// clocks change
+    pub(super) baud_rate: Option<Hertz>,
 }
 
 impl<UartP, RX, TX, RTS, CTS> core::ops::Deref for Uart<UartP, RX, TX, RTS, CTS> {
@@ -36,38 +45,40 @@ impl<UartP, RX, TX, RTS, CTS> core::ops::DerefMut for Uart<UartP, RX, TX, RTS, C
     }
 }
 
-//        |   RX   |   TX   |   RTS  |   CTS  | Periph ID |
-// -------+--------+--------+--------+--------+-----------|
-// UART   | PA08/A | PA09/A |        |        |     8     |
-// USART0 | PA10/A | PA11/A | PB25/A | PB26/A |    17     |
-// USART1 | PA12/A | PA13/A | PA14/A | PA15/A |    18     |
-// USART2 | PB21/A | PB20/A | PB22/A | PB23/A |    19     |
-//
-// atsam3_e:
-// USART3 | PD05/B | PD04/B |        |        |    20     |
-// atsam3x8h:
-// USART3 | PD05/B | PD04/B | PF05/A | PF04/A |    20     |
-
-/// The Uart derived from the UART peripheral, using pins Pa8 and Pa9 in
-/// peripheral A mode.  No hardware flow control.
-pub type Uart0 = Uart<UART, Pa8<PfA>, Pa9<PfA>, (), ()>;
-
-impl Uart0 {
+/// The Uart derived from the UART peripheral, by default using pins Pa8
+/// and Pa9 in peripheral A mode.  No hardware flow control.
+pub type Uart0<RX = Pa8<PfA>, TX = Pa9<PfA>> = Uart<UART, RX, TX, (), ()>;
+
+impl<RX: RxPin<UART>, TX: TxPin<UART>> Uart0<RX, TX> {
     /// Instantiate a representation of a UART, providing an interface
     /// configure, send, and receive on it.
-    pub fn new(uart_p: UART, rx: Pa8<PfA>, tx: Pa9<PfA>) -> Self {
+    pub fn new(uart_p: UART, rx: RX, tx: TX, clock: PeripheralClock<UART>) -> Self {
         let uart0 = Self {
             uart_p,
             _rx: rx,
             _tx: tx,
             _rts: (),
             _cts: (),
+            clock,
+            flow_control: false,
+            baud_rate: None,
         };
         uart0
             .cr
             .write_with_zero(|w| w.rxen().set_bit().txen().set_bit().rststa().set_bit());
         uart0
     }
+}
+
+impl<RX, TX> Uart0<RX, TX> {
+    /// Stop the UART, and release the peripheral, its pins and its clock
+    /// token.  The clock can then be disabled with
+    /// `SystemClocks::disable_peripheral_clock`.
+    pub fn free(self) -> (UART, RX, TX, PeripheralClock<UART>) {
+        self.cr
+            .write_with_zero(|w| w.rxdis().set_bit().txdis().set_bit());
+        (self.uart_p, self._rx, self._tx, self.clock)
+    }
 
     /// Set the serial line parity error correcting strategy.
     pub fn set_parity(&mut self, parity: PAR_A) {
@@ -93,28 +104,40 @@ impl Uart0 {
     }
 
     /// Set the serial line baud rate, which is configured to be a fraction of
-    /// the master clock speed.
-    pub fn set_baudrate<I: Into<Hertz>>(&mut self, baud_rate: I, mck: I) {
+    /// the peripheral clock speed.
+    pub fn set_baudrate<I: Into<Hertz>>(&mut self, baud_rate: I, clocks: &Clocks) {
+        let baud_rate = baud_rate.into();
+        self.baud_rate = Some(baud_rate);
         // cd = mck/(16*baudrate)
-        let cd = mck.into().0 / (baud_rate.into().0 << 4);
+        let cd = clocks.peripheral_clock(UART::PID).0 / (baud_rate.0 << 4);
         self.brgr.write(|w| unsafe { w.cd().bits(cd as u16) });
     }
 
     /// Return the serial line baud rate, calculated to be a fraction of the
-    /// master clock speed.
-    pub fn get_baudrate<I: Into<Hertz>>(&self, mck: I) -> Hertz {
+    /// peripheral clock speed.
+    pub fn get_baudrate(&self, clocks: &Clocks) -> Hertz {
         // baudrate = mck/(16*cd)
-        Hertz(mck.into().0 / ((self.brgr.read().cd().bits() as u32) << 4))
+        Hertz(clocks.peripheral_clock(UART::PID).0 / ((self.brgr.read().cd().bits() as u32) << 4))
     }
 }
 
-impl From<(UART, Pa8<PfA>, Pa9<PfA>)> for Uart0 {
-    fn from(parts: (UART, Pa8<PfA>, Pa9<PfA>)) -> Self {
-        Self::new(parts.0, parts.1, parts.2)
+impl<RX: RxPin<UART>, TX: TxPin<UART>> From<(UART, RX, TX, PeripheralClock<UART>)>
+    for Uart0<RX, TX>
+{
+    fn from(parts: (UART, RX, TX, PeripheralClock<UART>)) -> Self {
+        Self::new(parts.0, parts.1, parts.2, parts.3)
+    }
+}
+
+impl<RX, TX> ClockDependent for Uart0<RX, TX> {
+    fn clocks_changed(&mut self, clocks: &Clocks) {
+        if let Some(baud_rate) = self.baud_rate {
+            self.set_baudrate(baud_rate, clocks);
+        }
     }
 }
 
-impl serial::Write<u8> for Uart0 {
+impl<RX, TX> serial::Write<u8> for Uart0<RX, TX> {
     type Error = core::convert::Infallible;
 
     fn try_write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
@@ -138,7 +161,7 @@ impl serial::Write<u8> for Uart0 {
     }
 }
 
-impl serial::Read<u8> for Uart0 {
+impl<RX, TX> serial::Read<u8> for Uart0<RX, TX> {
     type Error = core::convert::Infallible;
 
     fn try_read(&mut self) -> nb::Result<u8, Self::Error> {
@@ -150,9 +173,9 @@ impl serial::Read<u8> for Uart0 {
     }
 }
 
-impl blocking::serial::write::Default<u8> for Uart0 {}
+impl<RX, TX> blocking::serial::write::Default<u8> for Uart0<RX, TX> {}
 
-impl fmt::Write for Uart0 {
+impl<RX, TX> fmt::Write for Uart0<RX, TX> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.try_bwrite_all(s.as_bytes()).map_err(|_| fmt::Error)
     }
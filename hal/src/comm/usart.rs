@@ -0,0 +1,364 @@
+use super::Uart;
+use crate::clock::PeripheralClock;
+use crate::mux::{CtsPin, RtsPin, RxPin, TxPin};
+use crate::target_device::{USART0, USART1, USART2};
+use crate::time::Hertz;
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+use crate::target_device::USART3;
+
+/// Return the clock divider (CD) and its fractional part in eighths (FP)
+/// giving the baud rate closest to, and no lower than, `baud_rate`, from
+/// the peripheral clock `pclk` sampled 16 times per bit.  Rates out of the
+/// divider's range are clamped to it: no faster than `pclk / 16`, and no
+/// slower than `pclk / (16 * 65535.875)`.
+pub(super) fn usart_divider(pclk: Hertz, baud_rate: Hertz) -> (u16, u8) {
+    // Baud rate = pclk / (16 * (CD + FP / 8)), so count eighths of CD.  CD
+    // = 0 would stop the baud rate clock.
+    let eighths = pclk
+        .0
+        .checked_div(baud_rate.0.saturating_mul(2))
+        .unwrap_or(u32::MAX)
+        .clamp(1 << 3, (0xffff << 3) | 0x7);
+    ((eighths >> 3) as u16, (eighths & 0x7) as u8)
+}
+
+// Inverse of `usart_divider`
+pub(super) fn usart_baud_rate(pclk: Hertz, cd: u16, fp: u8) -> Hertz {
+    let eighths = ((cd as u32) << 3) | fp as u32;
+    if eighths == 0 {
+        Hertz(0)
+    } else {
+        Hertz(pclk.0 / (eighths << 1))
+    }
+}
+
+// The parts shared by the drivers built on a USART, each implemented per
+// USART for a `$Type` whose first parameter is the USART.  The type owns
+// the peripheral field `$p`, the given pin fields, and `clock` and
+// `baud_rate` fields.
+//
+// - `base` resets the USART into an operating mode, and implements
+//   `free()`, `set_baudrate()` and `ClockDependent`, calling `$changed`
+//   first if given;
+// - `line` implements the parity and `get_baudrate()`, for the modes
+//   framing characters like the asynchronous mode;
+// - `serial` implements the embedded-hal serial traits, asking
+//   `$clear_to_send()` before each write and telling `$ready_to_receive()`
+//   whether a read is waiting, if given;
+// - `fmt` implements the blocking serial write and `fmt::Write`.
+macro_rules! usart_common {
+    (
+        base $USART:ident, $usart:ident, $Type:ident<$($T:ident),*>,
+        { $p:ident $(, $pin:ident: $Pin:ident)* } $(, $changed:ident)?
+    ) => {
+        impl<$($T),*> $Type<$USART, $($T),*> {
+            // Reset the USART, and configure it in `mode` for characters of
+            // 8 bits, without parity, and with 1 stop bit.  The receiver
+            // and transmitter are left disabled.
+            fn reset(&self, mode: $crate::target_device::$usart::mr::USART_MODE_A) {
+                self.cr().write_with_zero(|w| {
+                    w.rstrx()
+                        .set_bit()
+                        .rsttx()
+                        .set_bit()
+                        .rxdis()
+                        .set_bit()
+                        .txdis()
+                        .set_bit()
+                        .rststa()
+                        .set_bit()
+                });
+                self.mr().write_with_zero(|w| {
+                    w.usart_mode()
+                        .variant(mode)
+                        .usclks()
+                        .mck()
+                        .chrl()
+                        ._8_bit()
+                        .par()
+                        .no()
+                        .nbstop()
+                        ._1_bit()
+                        .chmode()
+                        .normal()
+                });
+            }
+
+            /// Stop the USART, and release the peripheral, its pins and its
+            /// clock token.  The clock can then be disabled with
+            /// `SystemClocks::disable_peripheral_clock`.
+            pub fn free(self) -> ($USART, $($Pin,)* $crate::clock::PeripheralClock<$USART>) {
+                self.cr()
+                    .write_with_zero(|w| w.rxdis().set_bit().txdis().set_bit());
+                (self.$p, $(self.$pin,)* self.clock)
+            }
+
+            /// Set the serial line baud rate, which is configured to be a
+            /// fraction of the peripheral clock speed.
+            pub fn set_baudrate<I: Into<$crate::time::Hertz>>(
+                &mut self,
+                baud_rate: I,
+                clocks: &$crate::clock::Clocks,
+            ) {
+                let baud_rate = baud_rate.into();
+                self.baud_rate = Some(baud_rate);
+                let (cd, fp) = $crate::comm::usart::usart_divider(
+                    clocks.peripheral_clock(<$USART as $crate::clock::PmcPeripheral>::PID),
+                    baud_rate,
+                );
+                self.brgr
+                    .write(|w| unsafe { w.cd().bits(cd).fp().bits(fp) });
+            }
+        }
+
+        impl<$($T),*> $crate::clock::ClockDependent for $Type<$USART, $($T),*> {
+            fn clocks_changed(&mut self, clocks: &$crate::clock::Clocks) {
+                $(self.$changed(clocks);)?
+                if let Some(baud_rate) = self.baud_rate {
+                    self.set_baudrate(baud_rate, clocks);
+                }
+            }
+        }
+    };
+
+    (line $USART:ident, $usart:ident, $Type:ident<$($T:ident),*>) => {
+        impl<$($T),*> $Type<$USART, $($T),*> {
+            /// Set the serial line parity error correcting strategy.
+            pub fn set_parity(&mut self, parity: $crate::target_device::$usart::mr::PAR_A) {
+                self.mr().modify(|_, w| w.par().variant(parity));
+            }
+
+            /// Get the serial line parity error correcting strategy.
+            pub fn get_parity(&self) -> $crate::target_device::$usart::mr::PAR_A {
+                match self.mr().read().par().variant() {
+                    $crate::target_device::generic::Variant::Val(v) => v,
+                    $crate::target_device::generic::Variant::Res(_) => unreachable!(),
+                }
+            }
+
+            /// Return the serial line baud rate, calculated to be a fraction
+            /// of the peripheral clock speed.
+            pub fn get_baudrate(&self, clocks: &$crate::clock::Clocks) -> $crate::time::Hertz {
+                let brgr = self.brgr.read();
+                $crate::comm::usart::usart_baud_rate(
+                    clocks.peripheral_clock(<$USART as $crate::clock::PmcPeripheral>::PID),
+                    brgr.cd().bits(),
+                    brgr.fp().bits(),
+                )
+            }
+        }
+    };
+
+    (
+        serial $USART:ident, $Type:ident<$($T:ident),*>
+        $(, $clear_to_send:ident, $ready_to_receive:ident)?
+    ) => {
+        impl<$($T),*> $crate::hal::serial::Write<u8> for $Type<$USART, $($T),*> {
+            type Error = core::convert::Infallible;
+
+            fn try_write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+                if !self.csr().read().txrdy().bits() $(|| !self.$clear_to_send())? {
+                    return Err(nb::Error::WouldBlock);
+                }
+
+                self.thr
+                    .write_with_zero(|w| unsafe { w.txchr().bits(word as u16) });
+
+                Ok(())
+            }
+
+            fn try_flush(&mut self) -> nb::Result<(), Self::Error> {
+                if !self.csr().read().txempty().bits() {
+                    return Err(nb::Error::WouldBlock);
+                }
+
+                Ok(())
+            }
+        }
+
+        impl<$($T),*> $crate::hal::serial::Read<u8> for $Type<$USART, $($T),*> {
+            type Error = core::convert::Infallible;
+
+            fn try_read(&mut self) -> nb::Result<u8, Self::Error> {
+                if !self.csr().read().rxrdy().bits() {
+                    $(self.$ready_to_receive(true);)?
+                    return Err(nb::Error::WouldBlock);
+                }
+
+                $(self.$ready_to_receive(false);)?
+                Ok(self.rhr.read().rxchr().bits() as u8)
+            }
+        }
+    };
+
+    (fmt $USART:ident, $Type:ident<$($T:ident),*>) => {
+        impl<$($T),*> $crate::hal::blocking::serial::write::Default<u8>
+            for $Type<$USART, $($T),*>
+        {
+        }
+
+        impl<$($T),*> core::fmt::Write for $Type<$USART, $($T),*> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                use $crate::hal::blocking::serial::Write;
+                self.try_bwrite_all(s.as_bytes())
+                    .map_err(|_| core::fmt::Error)
+            }
+        }
+    };
+}
+
+pub(super) use usart_common;
+
+macro_rules! usart {
+    ($USART:ident, $usart:ident, $Usart:ident) => {
+        /// The Uart derived from the USART peripheral of the same name, in
+        /// asynchronous mode.  RTS/CTS flow control is used when RTS and
+        /// CTS pins are given.
+        pub type $Usart<RX, TX, RTS = (), CTS = ()> = Uart<$USART, RX, TX, RTS, CTS>;
+
+        impl<RX: RxPin<$USART>, TX: TxPin<$USART>> Uart<$USART, RX, TX, (), ()> {
+            /// Instantiate a representation of a USART in asynchronous
+            /// mode without flow control, providing an interface to
+            /// configure, send, and receive on it.
+            pub fn new(usart_p: $USART, rx: RX, tx: TX, clock: PeripheralClock<$USART>) -> Self {
+                let usart = Self {
+                    uart_p: usart_p,
+                    _rx: rx,
+                    _tx: tx,
+                    _rts: (),
+                    _cts: (),
+                    flow_control: false,
+                    clock,
+                    baud_rate: None,
+                };
+                usart.enable();
+                usart
+            }
+        }
+
+        impl<RX, TX, RTS, CTS> Uart<$USART, RX, TX, RTS, CTS>
+        where
+            RX: RxPin<$USART>,
+            TX: TxPin<$USART>,
+            RTS: RtsPin<$USART>,
+            CTS: CtsPin<$USART>,
+        {
+            /// Instantiate a representation of a USART in asynchronous
+            /// mode with RTS/CTS flow control.  Writes wait while CTS is
+            /// high.  RTS is pulled low, letting the peer send, while a
+            /// read finds no character waiting, and is raised again once a
+            /// character has been read; the peer is expected to stop
+            /// sending within a character of RTS rising.
+            pub fn new_with_flow_control(
+                usart_p: $USART,
+                rx: RX,
+                tx: TX,
+                rts: RTS,
+                cts: CTS,
+                clock: PeripheralClock<$USART>,
+            ) -> Self {
+                let usart = Self {
+                    uart_p: usart_p,
+                    _rx: rx,
+                    _tx: tx,
+                    _rts: rts,
+                    _cts: cts,
+                    flow_control: true,
+                    clock,
+                    baud_rate: None,
+                };
+                usart.enable();
+                usart
+            }
+        }
+
+        impl<RX, TX, RTS, CTS> Uart<$USART, RX, TX, RTS, CTS> {
+            // The USART's own hardware handshaking mode only drives RTS
+            // from the status of a PDC receive buffer, so the normal mode
+            // is used, with RTS driven through the control register.  RTS
+            // starts high, until a read is waiting.
+            fn enable(&self) {
+                self.reset(crate::target_device::$usart::mr::USART_MODE_A::NORMAL);
+                self.ready_to_receive(false);
+                self.cr()
+                    .write_with_zero(|w| w.rxen().set_bit().txen().set_bit());
+            }
+
+            fn clear_to_send(&self) -> bool {
+                !self.flow_control || !self.csr().read().cts().bits()
+            }
+
+            fn ready_to_receive(&self, ready: bool) {
+                if self.flow_control {
+                    self.cr().write_with_zero(|w| {
+                        if ready {
+                            w.rtsen().set_bit()
+                        } else {
+                            w.rtsdis().set_bit()
+                        }
+                    });
+                }
+            }
+
+            /// Set the serial channel echo/loopback mode.
+            pub fn set_channel_mode(
+                &mut self,
+                ch_mode: crate::target_device::$usart::mr::CHMODE_A,
+            ) {
+                self.mr().modify(|_, w| w.chmode().variant(ch_mode));
+            }
+
+            /// Get the serial channel echo/loopback mode.
+            pub fn get_channel_mode(&self) -> crate::target_device::$usart::mr::CHMODE_A {
+                self.mr().read().chmode().variant()
+            }
+        }
+
+        usart_common!(
+            base $USART, $usart, Uart<RX, TX, RTS, CTS>,
+            { uart_p, _rx: RX, _tx: TX, _rts: RTS, _cts: CTS }
+        );
+        usart_common!(line $USART, $usart, Uart<RX, TX, RTS, CTS>);
+        usart_common!(
+            serial $USART, Uart<RX, TX, RTS, CTS>,
+            clear_to_send, ready_to_receive
+        );
+        usart_common!(fmt $USART, Uart<RX, TX, RTS, CTS>);
+    };
+}
+
+usart!(USART0, usart0, Usart0);
+usart!(USART1, usart1, Usart1);
+usart!(USART2, usart2, Usart2);
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+usart!(USART3, usart3, Usart3);
+
+#[cfg(test)]
+mod tests {
+    use crate::comm::usart::*;
+
+    #[test]
+    fn usart_divider_round_trips() {
+        let mck = Hertz(84_000_000);
+        // 84 MHz / (16 * 115200) = 45.57, so CD = 45 and FP = 4
+        assert_eq!(usart_divider(mck, Hertz(115_200)), (45, 4));
+        assert_eq!(usart_baud_rate(mck, 45, 4), Hertz(115_384));
+        assert_eq!(usart_divider(mck, Hertz(9_600)), (546, 7));
+        assert_eq!(usart_baud_rate(mck, 0, 0), Hertz(0));
+    }
+
+    #[test]
+    fn usart_divider_clamps_to_range() {
+        let mck = Hertz(84_000_000);
+        // Above 84 MHz / 16 = 5.25 MBd, CD would be 0
+        assert_eq!(usart_divider(mck, Hertz(5_250_000)), (1, 0));
+        assert_eq!(usart_divider(mck, Hertz(10_000_000)), (1, 0));
+        assert_eq!(usart_divider(mck, Hertz(u32::MAX)), (1, 0));
+        // Below 84 MHz / (16 * 65535.875) = 80.1 Bd, CD would overflow
+        assert_eq!(usart_divider(mck, Hertz(81)), (64_814, 6));
+        assert_eq!(usart_divider(mck, Hertz(50)), (0xffff, 7));
+        assert_eq!(usart_divider(mck, Hertz(0)), (0xffff, 7));
+    }
+}
@@ -3,6 +3,7 @@
 use cortex_m::peripheral::syst::SystClkSource;
 use cortex_m::peripheral::SYST;
 
+use crate::clock::{ClockDependent, Clocks};
 use crate::hal::blocking::delay::{DelayMs, DelayUs};
 use crate::time::Hertz;
 
@@ -14,13 +15,14 @@ pub struct Delay<PERIPH> {
 
 /// System timer (SysTick) as a delay provider
 impl Delay<SYST> {
-    /// Configures the system timer (SysTick) as a delay provider
-    pub fn new(mut syst: SYST, core_speed: Hertz) -> Self {
+    /// Configures the system timer (SysTick) as a delay provider, clocked
+    /// from the master clock.
+    pub fn new(mut syst: SYST, clocks: &Clocks) -> Self {
         syst.set_clock_source(SystClkSource::Core);
 
         Delay {
             p: syst,
-            refclock: core_speed,
+            refclock: clocks.mck(),
         }
     }
 
@@ -30,6 +32,12 @@ impl Delay<SYST> {
     }
 }
 
+impl ClockDependent for Delay<SYST> {
+    fn clocks_changed(&mut self, clocks: &Clocks) {
+        self.refclock = clocks.mck();
+    }
+}
+
 impl DelayMs<u32> for Delay<SYST> {
     type Error = core::convert::Infallible;
     fn try_delay_ms(&mut self, ms: u32) -> Result<(), Self::Error> {
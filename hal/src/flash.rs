@@ -1,7 +1,23 @@
 //! Configuring the embedded flash controllers.
 use crate::target_device;
+use crate::time::Hertz;
 use target_device::{EFC0, EFC1};
 
+// Highest master clock frequency at which the flash can be read with the
+// wait state count matching the index.  These are the datasheet's limits
+// for the lowest core supply voltage (VDDCORE = 1.62V), which hold whatever
+// the board supplies.
+const FWS_MAX_MCK: [u32; 5] = [17_000_000, 45_000_000, 58_000_000, 70_000_000, 78_000_000];
+
+/// Return the minimum number of additional cycles the flash needs for read
+/// operations to complete when running at the given master clock frequency.
+pub fn wait_states_for(mck: Hertz) -> u8 {
+    FWS_MAX_MCK
+        .iter()
+        .position(|&max| mck.0 <= max)
+        .unwrap_or(FWS_MAX_MCK.len()) as u8
+}
+
 /// Flash controller
 pub struct FlashController<EFCn> {
     efc: EFCn,
@@ -24,12 +40,16 @@ impl<E> core::ops::DerefMut for FlashController<E> {
 /// Embedded flash controller 0 (EFC0)
 pub type FlashController0 = FlashController<EFC0>;
 impl FlashController<EFC0> {
-    /// Instantiate a flash controller object, and initialize it to a safe value
+    /// Instantiate a flash controller object.  The flash wait states are
+    /// left untouched; they are managed by the clock configuration as the
+    /// master clock changes.
     pub fn new(efc: EFC0) -> Self {
-        let mut tmp = Self { efc };
-        // Init flash wait state to a safe value
-        tmp.set_op_cycle_count(4);
-        tmp
+        Self { efc }
+    }
+
+    /// Release the flash controller peripheral.
+    pub fn free(self) -> EFC0 {
+        self.efc
     }
 
     /// Set the number of additional cycles to wait for read/write operations
@@ -43,6 +63,13 @@ impl FlashController<EFC0> {
     pub fn get_op_cycle_count(&self) -> u8 {
         self.efc.fmr.read().fws().bits()
     }
+
+    /// Set the minimum number of additional cycles to wait for read/write
+    /// operations to complete that is safe at the given master clock
+    /// frequency.
+    pub fn set_wait_states_for(&mut self, mck: Hertz) {
+        self.set_op_cycle_count(wait_states_for(mck));
+    }
 }
 
 impl From<EFC0> for FlashController0 {
@@ -54,12 +81,16 @@ impl From<EFC0> for FlashController0 {
 /// Embedded flash controller 1 (EFC1)
 pub type FlashController1 = FlashController<EFC1>;
 impl FlashController<EFC1> {
-    /// Instantiate a flash controller object, and initialize it to a safe value
+    /// Instantiate a flash controller object.  The flash wait states are
+    /// left untouched; they are managed by the clock configuration as the
+    /// master clock changes.
     pub fn new(efc: EFC1) -> Self {
-        let mut tmp = Self { efc };
-        // Init flash wait state to a safe value
-        tmp.set_op_cycle_count(4);
-        tmp
+        Self { efc }
+    }
+
+    /// Release the flash controller peripheral.
+    pub fn free(self) -> EFC1 {
+        self.efc
     }
 
     /// Set the number of additional cycles to wait for read/write operations
@@ -73,6 +104,13 @@ impl FlashController<EFC1> {
     pub fn get_op_cycle_count(&self) -> u8 {
         self.efc.fmr.read().fws().bits()
     }
+
+    /// Set the minimum number of additional cycles to wait for read/write
+    /// operations to complete that is safe at the given master clock
+    /// frequency.
+    pub fn set_wait_states_for(&mut self, mck: Hertz) {
+        self.set_op_cycle_count(wait_states_for(mck));
+    }
 }
 
 impl From<EFC1> for FlashController1 {
@@ -80,3 +118,24 @@ impl From<EFC1> for FlashController1 {
         Self::new(efc1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::flash::*;
+
+    #[test]
+    fn wait_states_at_boundaries() {
+        assert_eq!(wait_states_for(Hertz(4_000_000)), 0);
+        assert_eq!(wait_states_for(Hertz(17_000_000)), 0);
+        assert_eq!(wait_states_for(Hertz(17_000_001)), 1);
+        assert_eq!(wait_states_for(Hertz(45_000_000)), 1);
+        assert_eq!(wait_states_for(Hertz(45_000_001)), 2);
+        assert_eq!(wait_states_for(Hertz(58_000_000)), 2);
+        assert_eq!(wait_states_for(Hertz(58_000_001)), 3);
+        assert_eq!(wait_states_for(Hertz(70_000_000)), 3);
+        assert_eq!(wait_states_for(Hertz(70_000_001)), 4);
+        assert_eq!(wait_states_for(Hertz(78_000_000)), 4);
+        assert_eq!(wait_states_for(Hertz(78_000_001)), 5);
+        assert_eq!(wait_states_for(Hertz(84_000_000)), 5);
+    }
+}
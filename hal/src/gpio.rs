@@ -13,11 +13,20 @@ use crate::target_device::{PIOA, PIOB, PIOC, PIOD};
 #[cfg(feature = "sam3x8h")]
 use crate::target_device::{PIOA, PIOB, PIOC, PIOD, PIOE, PIOF};
 
+use crate::clock::{Clocks, PeripheralClock};
+use crate::target_device::pioa;
+use crate::time::{Hertz, Miliseconds};
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 /// PIO controller configuration register block.
 pub struct PioGroup<PIOn> {
     group: PIOn,
+    /// Pins already handed out, one bit per pin
+    taken: u32,
+    /// Proof that the controller's clock is running, which input reads,
+    /// input filters and pin interrupts depend on
+    _clock: PeripheralClock<PIOn>,
 }
 
 impl<PIOn> core::ops::Deref for PioGroup<PIOn> {
@@ -38,9 +47,11 @@ impl<PIOn> core::ops::DerefMut for PioGroup<PIOn> {
 pub struct Unconfigured;
 
 /// Represents a pin configured for input.
-/// The MODE type is typically one of `Floating` or `PullUp`.
-pub struct Input<MODE> {
+/// The MODE type is typically one of `Floating` or `PullUp`, and the FILTER
+/// type one of `Unfiltered`, `GlitchFiltered`, or `Debounced`.
+pub struct Input<MODE, FILTER = Unfiltered> {
     _mode: PhantomData<MODE>,
+    _filter: PhantomData<FILTER>,
 }
 
 /// Represents a pin configured for output.
@@ -58,6 +69,29 @@ pub struct Floating;
 /// Pulled up Input
 pub struct PullUp;
 
+/// Input sampled without filtering
+pub struct Unfiltered;
+/// Input ignoring pulses shorter than half a master clock cycle
+pub struct GlitchFiltered;
+/// Input ignoring pulses shorter than the PIO group's debounce period
+pub struct Debounced;
+
+// Largest SCDR DIV value
+const DEBOUNCE_DIV_MAX: u32 = 0x3fff;
+
+/// Return the SCDR DIV value giving the shortest debounce period of at least
+/// `period` with the given slow clock.  The filter samples the input every
+/// 2 * (DIV + 1) slow clock cycles.
+pub fn debounce_divider(period: Miliseconds, slck: Hertz) -> u16 {
+    let cycles = (period.0 as u64 * slck.0 as u64).div_ceil(1000);
+    let div = cycles.div_ceil(2).saturating_sub(1);
+    if div > DEBOUNCE_DIV_MAX as u64 {
+        DEBOUNCE_DIV_MAX as u16
+    } else {
+        div as u16
+    }
+}
+
 /// Totem Pole aka Push-Pull
 pub struct PushPull;
 /// Open drain output
@@ -68,223 +102,869 @@ pub struct PfA;
 /// Peripheral Function B
 pub struct PfB;
 
-macro_rules! pin {
-    (
-        $group:ident,
-        $PinType:ident,
-        $pin_ident:ident,
-        $pin_no:expr
-    ) => {
-        crate::paste! {
-        /// Represents the IO pin with the matching name.
-        pub struct $PinType<MODE> {
-            _mode: PhantomData<MODE>,
-        }
-        } // end paste
-
-        crate::paste! {
-        /// Represents an unconfigured IO pin.
-        pub type [<Unconfigured $PinType>] = $PinType<Unconfigured>;
-        impl Default for [<Unconfigured $PinType>] {
-            fn default() -> Self {
-                Self::new()
-            }
+/// Input conditions that can raise a PIO interrupt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptEvent {
+    /// Low to high transition
+    RisingEdge,
+    /// High to low transition
+    FallingEdge,
+    /// Any transition
+    BothEdges,
+    /// Raised for as long as the input is high
+    HighLevel,
+    /// Raised for as long as the input is low
+    LowLevel,
+}
+
+/// Interrupts pending on a PIO controller, one bit per pin, as returned by
+/// `PioGroup::pending_interrupts()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PioInterrupts {
+    bits: u32,
+}
+
+impl PioInterrupts {
+    /// Interrupts pending, one bit per pin.
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Return whether an interrupt is pending on any of the pins in `mask`,
+    /// such as a pin's `MASK`.
+    pub fn is_pending(&self, mask: u32) -> bool {
+        self.bits & mask != 0
+    }
+
+    /// Iterate over the numbers of the pins with an interrupt pending.
+    pub fn pins(&self) -> impl Iterator<Item = u8> {
+        let bits = self.bits;
+        (0..32u8).filter(move |n| bits & (1 << n) != 0)
+    }
+}
+
+/// Interrupt handlers for the pins of a PIO controller, called by
+/// `dispatch()` for each pin with an interrupt pending.
+pub struct PioHandlers {
+    handlers: [Option<fn()>; 32],
+}
+
+impl Default for PioHandlers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PioHandlers {
+    /// Instantiate a table with no handlers registered.
+    pub const fn new() -> Self {
+        Self {
+            handlers: [None; 32],
         }
+    }
 
-        impl [<Unconfigured $PinType>] {
-            /// Instantiate the pin in an unconfigured state
-            pub fn new() -> Self {
-                Self {
-                    _mode: PhantomData,
-                }
+    /// Call `handler` for interrupts on any of the pins in `mask`.
+    pub fn register(&mut self, mask: u32, handler: fn()) {
+        for n in 0..32 {
+            if mask & (1 << n) != 0 {
+                self.handlers[n] = Some(handler);
             }
         }
-        } // end paste
-
-        impl<MODE> $PinType<MODE> {
-            /// Configures the pin to operate as a floating input
-            pub fn into_floating_input(self) -> $PinType<Input<Floating>> {
-                crate::paste! {
-                // Enable PIO (not peripheral) mode
-                unsafe {(*$group::ptr()).per.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Disable output mode (input)
-                unsafe {(*$group::ptr()).odr.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Disable pullup mode (floating)
-                unsafe {(*$group::ptr()).pudr.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                } // end paste
-                $PinType { _mode: PhantomData }
-            }
+    }
 
-            /// Configures the pin to operate as a pulled-up input
-            pub fn into_pull_up_input(self) -> $PinType<Input<PullUp>> {
-                crate::paste! {
-                // Enable PIO (not peripheral) mode
-                unsafe {(*$group::ptr()).per.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Disable output mode (input)
-                unsafe {(*$group::ptr()).odr.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Enable pullup mode
-                unsafe {(*$group::ptr()).puer.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                } // end paste
-                $PinType { _mode: PhantomData }
+    /// Stop handling interrupts on the pins in `mask`.
+    pub fn unregister(&mut self, mask: u32) {
+        for n in 0..32 {
+            if mask & (1 << n) != 0 {
+                self.handlers[n] = None;
             }
+        }
+    }
 
-            /// Configures the pin to operate as an open-drain output
-            pub fn into_open_drain_output(self) -> $PinType<Output<OpenDrain>> {
-                crate::paste! {
-                // Enable PIO (not peripheral) mode
-                unsafe {(*$group::ptr()).per.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Enable output mode
-                unsafe {(*$group::ptr()).oer.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Enable open-drain (multi-driver) mode
-                unsafe {(*$group::ptr()).mder.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                } // end paste
-                $PinType { _mode: PhantomData }
+    /// Call the handler of every pin with an interrupt pending.  A handler
+    /// registered for several pins is called once for each of them.
+    pub fn dispatch(&self, pending: PioInterrupts) {
+        for n in pending.pins() {
+            if let Some(handler) = self.handlers[n as usize] {
+                handler();
             }
+        }
+    }
+}
 
-            /// Configures the pin to operate as an push-pull output
-            pub fn into_push_pull_output(self) -> $PinType<Output<PushPull>> {
-                crate::paste! {
-                // Enable PIO (not peripheral) mode
-                unsafe {(*$group::ptr()).per.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Enable output mode
-                unsafe {(*$group::ptr()).oer.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Disable open-drain mode
-                unsafe {(*$group::ptr()).mddr.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Disable pull-up mode
-                unsafe {(*$group::ptr()).pudr.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                } // end paste
-                $PinType { _mode: PhantomData }
-            }
+/// Accumulates the interrupts pending on a PIO controller, so they can be
+/// recorded by the interrupt handler and consumed by the main loop.  Meant
+/// to be placed in a `static`.
+pub struct PioInterruptFlags {
+    bits: AtomicU32,
+}
 
-            /// Configures the pin to function as the primary (A) attached peripheral.
-            pub fn into_peripheral_a(self) -> $PinType<PfA> {
-                crate::paste! {
-                // Disable PIO (enable peripheral) mode
-                unsafe {(*$group::ptr()).pdr.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Select Peripheral A (0 -> A, 1 -> B)
-                unsafe {(*$group::ptr()).absr.modify(|_, w| w.[<p $pin_no>]().clear_bit());}
-                } // end paste
-                $PinType { _mode: PhantomData }
-            }
+impl Default for PioInterruptFlags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-            /// Configures the pin to function as the alternate (B) attached peripheral.
-            pub fn into_peripheral_b(self) -> $PinType<PfB> {
-                crate::paste! {
-                // Disable PIO (enable peripheral) mode
-                unsafe {(*$group::ptr()).pdr.write_with_zero(|w| w.[<p $pin_no>]().set_bit());}
-                // Select Peripheral B (0 -> A, 1 -> B)
-                unsafe {(*$group::ptr()).absr.modify(|_, w| w.[<p $pin_no>]().set_bit());}
-                } // end paste
-                $PinType { _mode: PhantomData }
-            }
+impl PioInterruptFlags {
+    /// Instantiate with no interrupts recorded.
+    pub const fn new() -> Self {
+        Self {
+            bits: AtomicU32::new(0),
         }
+    }
 
-        impl<MODE> $PinType<Output<MODE>> {
-            /// Toggle the logic level of the pin; if it is currently high, set it low
-            /// and vice-versa.
-            pub fn toggle(&mut self) {
-                self.toggle_impl();
-            }
+    /// Record the pending interrupts, keeping any not yet taken.
+    pub fn record(&self, pending: PioInterrupts) {
+        self.bits.fetch_or(pending.bits, Ordering::AcqRel);
+    }
+
+    /// Return whether an interrupt was recorded for any of the pins in
+    /// `mask`, and clear them.
+    pub fn take(&self, mask: u32) -> bool {
+        self.bits.fetch_and(!mask, Ordering::AcqRel) & mask != 0
+    }
+}
+
+// All PIO controllers share PIOA's register layout, so a pin is fully
+// described by its controller's registers and its bit mask.
+type PioRegisters = *const pioa::RegisterBlock;
+
+/// Pin configuration, as tracked at runtime by `DynPin`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DynPinMode {
+    /// Left as it was at reset
+    Unconfigured,
+    /// Floating input
+    FloatingInput,
+    /// Pulled up input
+    PullUpInput,
+    /// Open drain output
+    OpenDrainOutput,
+    /// Push-pull output
+    PushPullOutput,
+    /// Peripheral function A
+    PeripheralA,
+    /// Peripheral function B
+    PeripheralB,
+}
+
+impl DynPinMode {
+    fn is_input(self) -> bool {
+        matches!(self, DynPinMode::FloatingInput | DynPinMode::PullUpInput)
+    }
+
+    fn is_output(self) -> bool {
+        matches!(
+            self,
+            DynPinMode::OpenDrainOutput | DynPinMode::PushPullOutput
+        )
+    }
+}
+
+/// Errors reported by `DynPin` when used in a mode that doesn't support
+/// the operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DynPinError {
+    /// The pin isn't configured as an input
+    NotAnInput,
+    /// The pin isn't configured as an output
+    NotAnOutput,
+}
+
+/// Pin modes that have a runtime equivalent in `DynPinMode`.
+pub trait PinMode {
+    /// Runtime equivalent of the mode
+    const DYN: DynPinMode;
+}
 
-            fn toggle_impl(&mut self) {
-                if self.is_high() {
-                    self.set_low_impl();
+impl PinMode for Unconfigured {
+    const DYN: DynPinMode = DynPinMode::Unconfigured;
+}
+
+impl<FILTER> PinMode for Input<Floating, FILTER> {
+    const DYN: DynPinMode = DynPinMode::FloatingInput;
+}
+
+impl<FILTER> PinMode for Input<PullUp, FILTER> {
+    const DYN: DynPinMode = DynPinMode::PullUpInput;
+}
+
+impl PinMode for Output<OpenDrain> {
+    const DYN: DynPinMode = DynPinMode::OpenDrainOutput;
+}
+
+impl PinMode for Output<PushPull> {
+    const DYN: DynPinMode = DynPinMode::PushPullOutput;
+}
+
+impl PinMode for PfA {
+    const DYN: DynPinMode = DynPinMode::PeripheralA;
+}
+
+impl PinMode for PfB {
+    const DYN: DynPinMode = DynPinMode::PeripheralB;
+}
+
+// Apply `mode` to the pins in `mask`, as the typed `into_*()` conversions do
+fn configure_pins(regs: PioRegisters, mask: u32, mode: DynPinMode) {
+    let pio = unsafe { &*regs };
+    unsafe {
+        match mode {
+            DynPinMode::Unconfigured => (),
+            DynPinMode::FloatingInput | DynPinMode::PullUpInput => {
+                pio.per.write_with_zero(|w| w.bits(mask));
+                pio.odr.write_with_zero(|w| w.bits(mask));
+                if mode == DynPinMode::PullUpInput {
+                    pio.puer.write_with_zero(|w| w.bits(mask));
                 } else {
-                    self.set_high_impl();
+                    pio.pudr.write_with_zero(|w| w.bits(mask));
                 }
+                pio.ifdr.write_with_zero(|w| w.bits(mask));
             }
-
-            /// Set the logic level of the pin high.
-            pub fn set_high(&mut self) {
-                self.set_high_impl();
+            DynPinMode::OpenDrainOutput => {
+                pio.per.write_with_zero(|w| w.bits(mask));
+                pio.oer.write_with_zero(|w| w.bits(mask));
+                pio.mder.write_with_zero(|w| w.bits(mask));
             }
-
-            fn set_high_impl(&mut self) {
-                crate::paste! {
-                unsafe {(*$group::ptr()).sodr.write_with_zero(|w| w.[<p $pin_no>]().bit(true));}
-                } // end paste
+            DynPinMode::PushPullOutput => {
+                pio.per.write_with_zero(|w| w.bits(mask));
+                pio.oer.write_with_zero(|w| w.bits(mask));
+                pio.mddr.write_with_zero(|w| w.bits(mask));
+                pio.pudr.write_with_zero(|w| w.bits(mask));
             }
-
-            /// Set the logic level of the pin low.
-            pub fn set_low(&mut self) {
-                self.set_low_impl();
+            // ABSR has no set/clear registers; the read-modify-write runs
+            // in a critical section so it can't undo another pin's change
+            DynPinMode::PeripheralA => {
+                pio.pdr.write_with_zero(|w| w.bits(mask));
+                cortex_m::interrupt::free(|_| {
+                    pio.absr.modify(|r, w| w.bits(r.bits() & !mask));
+                });
             }
-
-            fn set_low_impl(&mut self) {
-                crate::paste! {
-                unsafe {(*$group::ptr()).codr.write_with_zero(|w| w.[<p $pin_no>]().bit(true));}
-                } // end paste
+            DynPinMode::PeripheralB => {
+                pio.pdr.write_with_zero(|w| w.bits(mask));
+                cortex_m::interrupt::free(|_| {
+                    pio.absr.modify(|r, w| w.bits(r.bits() | mask));
+                });
             }
         }
+    }
+}
 
-        impl<MODE> ToggleableOutputPin for $PinType<Output<MODE>> {
-            type Error = core::convert::Infallible;
+/// Represents any IO pin, with its mode encoded in its type like the
+/// pin-specific types.  Obtained with a pin's `degrade()`, so that pins of
+/// different PIO groups can be stored together, such as in an array.
+pub struct AnyPin<MODE> {
+    regs: PioRegisters,
+    mask: u32,
+    _mode: PhantomData<MODE>,
+}
 
-            fn try_toggle(&mut self) -> Result<(), Self::Error> {
-                self.toggle_impl();
-                Ok(())
-            }
+// Each pin is owned by a single `AnyPin`, which only changes its own bit in
+// the PIO registers: through the set/clear registers, or, for ABSR, with a
+// read-modify-write inside a critical section.
+unsafe impl<MODE> Send for AnyPin<MODE> {}
+
+impl<MODE> AnyPin<MODE> {
+    /// Bit mask of this pin within its PIO controller's registers
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    /// Test the logic level of the pin; if it is currently high, return true.
+    pub fn is_high(&self) -> bool {
+        unsafe { (*self.regs).pdsr.read().bits() & self.mask != 0 }
+    }
+
+    /// Test the logic level of the pin; if it is currently low, return true.
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    /// Convert into a pin whose mode is tracked at runtime.
+    pub fn into_dyn(self) -> DynPin
+    where
+        MODE: PinMode,
+    {
+        DynPin {
+            regs: self.regs,
+            mask: self.mask,
+            mode: MODE::DYN,
         }
+    }
+}
 
-        impl<MODE> OutputPin for $PinType<Output<MODE>> {
-            type Error = core::convert::Infallible;
+impl<MODE> AnyPin<Output<MODE>> {
+    /// Toggle the logic level of the pin; if it is currently high, set it low
+    /// and vice-versa.
+    pub fn toggle(&mut self) {
+        if self.is_high() {
+            self.set_low();
+        } else {
+            self.set_high();
+        }
+    }
 
-            fn try_set_high(&mut self) -> Result<(), Self::Error> {
-                self.set_high_impl();
-                Ok(())
-            }
+    /// Set the logic level of the pin high.
+    pub fn set_high(&mut self) {
+        unsafe { (*self.regs).sodr.write_with_zero(|w| w.bits(self.mask)) }
+    }
 
-            fn try_set_low(&mut self) -> Result<(), Self::Error> {
-                self.set_low_impl();
-                Ok(())
-            }
+    /// Set the logic level of the pin low.
+    pub fn set_low(&mut self) {
+        unsafe { (*self.regs).codr.write_with_zero(|w| w.bits(self.mask)) }
+    }
+}
+
+impl<MODE> ToggleableOutputPin for AnyPin<Output<MODE>> {
+    type Error = core::convert::Infallible;
+
+    fn try_toggle(&mut self) -> Result<(), Self::Error> {
+        self.toggle();
+        Ok(())
+    }
+}
+
+impl<MODE> OutputPin for AnyPin<Output<MODE>> {
+    type Error = core::convert::Infallible;
+
+    fn try_set_high(&mut self) -> Result<(), Self::Error> {
+        self.set_high();
+        Ok(())
+    }
+
+    fn try_set_low(&mut self) -> Result<(), Self::Error> {
+        self.set_low();
+        Ok(())
+    }
+}
+
+impl<MODE> StatefulOutputPin for AnyPin<Output<MODE>> {
+    fn try_is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_high())
+    }
+
+    fn try_is_set_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_low())
+    }
+}
+
+impl<MODE> InputPin for AnyPin<MODE> {
+    type Error = core::convert::Infallible;
+
+    fn try_is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_high())
+    }
+
+    fn try_is_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_low())
+    }
+}
+
+/// Represents any IO pin, with its mode tracked at runtime rather than in
+/// its type.  Operations that don't suit the current mode return a
+/// `DynPinError`.
+pub struct DynPin {
+    regs: PioRegisters,
+    mask: u32,
+    mode: DynPinMode,
+}
+
+// See `AnyPin`
+unsafe impl Send for DynPin {}
+
+impl DynPin {
+    /// Bit mask of this pin within its PIO controller's registers
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    /// Return the current mode of the pin.
+    pub fn mode(&self) -> DynPinMode {
+        self.mode
+    }
+
+    /// Reconfigure the pin.  Switching to `Unconfigured` leaves the pin's
+    /// configuration untouched.
+    pub fn set_mode(&mut self, mode: DynPinMode) {
+        configure_pins(self.regs, self.mask, mode);
+        self.mode = mode;
+    }
+
+    /// Test the logic level of the pin; if it is currently high, return
+    /// true.  The pin must be an input or an output.
+    pub fn is_high(&self) -> Result<bool, DynPinError> {
+        if self.mode.is_input() || self.mode.is_output() {
+            Ok(unsafe { (*self.regs).pdsr.read().bits() } & self.mask != 0)
+        } else {
+            Err(DynPinError::NotAnInput)
         }
+    }
 
-        impl<MODE> StatefulOutputPin for $PinType<Output<MODE>> {
-            fn try_is_set_high(&self) -> Result<bool, Self::Error> {
-                Ok(self.is_high())
-            }
+    /// Test the logic level of the pin; if it is currently low, return
+    /// true.  The pin must be an input or an output.
+    pub fn is_low(&self) -> Result<bool, DynPinError> {
+        self.is_high().map(|high| !high)
+    }
 
-            fn try_is_set_low(&self) -> Result<bool, Self::Error> {
-                Ok(self.is_low())
-            }
+    /// Set the logic level of the pin high.  The pin must be an output.
+    pub fn set_high(&mut self) -> Result<(), DynPinError> {
+        self.check_output()?;
+        unsafe { (*self.regs).sodr.write_with_zero(|w| w.bits(self.mask)) }
+        Ok(())
+    }
+
+    /// Set the logic level of the pin low.  The pin must be an output.
+    pub fn set_low(&mut self) -> Result<(), DynPinError> {
+        self.check_output()?;
+        unsafe { (*self.regs).codr.write_with_zero(|w| w.bits(self.mask)) }
+        Ok(())
+    }
+
+    /// Toggle the logic level of the pin.  The pin must be an output.
+    pub fn toggle(&mut self) -> Result<(), DynPinError> {
+        if self.is_high()? {
+            self.set_low()
+        } else {
+            self.set_high()
         }
+    }
 
-        impl<MODE> $PinType<MODE> {
-            /// Test the logic level of the pin; if it is currently high, return true.
-            /// If $group is not currently clocked, this will return the value from
-            /// when it was last clocked.
-            pub fn is_high(&self) -> bool {
-                crate::paste! {
-                unsafe {(*$group::ptr()).pdsr.read().[<p $pin_no>]().bits()}
-                } // end paste
-            }
+    fn check_output(&self) -> Result<(), DynPinError> {
+        if self.mode.is_output() {
+            Ok(())
+        } else {
+            Err(DynPinError::NotAnOutput)
+        }
+    }
+}
+
+impl ToggleableOutputPin for DynPin {
+    type Error = DynPinError;
+
+    fn try_toggle(&mut self) -> Result<(), Self::Error> {
+        self.toggle()
+    }
+}
+
+impl OutputPin for DynPin {
+    type Error = DynPinError;
+
+    fn try_set_high(&mut self) -> Result<(), Self::Error> {
+        self.set_high()
+    }
 
-            /// Test the logic level of the pin; if it is currently low, return true.
-            /// If $group is not currently clocked, this will return the value from
-            /// when it was last clocked.
-            pub fn is_low(&self) -> bool {
-                !self.is_high()
+    fn try_set_low(&mut self) -> Result<(), Self::Error> {
+        self.set_low()
+    }
+}
+
+impl StatefulOutputPin for DynPin {
+    fn try_is_set_high(&self) -> Result<bool, Self::Error> {
+        self.check_output()?;
+        self.is_high()
+    }
+
+    fn try_is_set_low(&self) -> Result<bool, Self::Error> {
+        self.check_output()?;
+        self.is_low()
+    }
+}
+
+impl InputPin for DynPin {
+    type Error = DynPinError;
+
+    fn try_is_high(&self) -> Result<bool, Self::Error> {
+        self.is_high()
+    }
+
+    fn try_is_low(&self) -> Result<bool, Self::Error> {
+        self.is_low()
+    }
+}
+
+impl<MODE: PinMode> From<AnyPin<MODE>> for DynPin {
+    fn from(pin: AnyPin<MODE>) -> Self {
+        pin.into_dyn()
+    }
+}
+
+/// Errors reported when building a `ParallelPort`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParallelPortError {
+    /// The pins don't all belong to the same PIO group
+    MixedGroups,
+    /// The same pin was given more than once
+    DuplicatePin,
+}
+
+// Move bit `i` of `value` to bit `shifts[i]`
+fn scatter_bits(value: u32, shifts: &[u8]) -> u32 {
+    shifts
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &shift)| bits | (((value >> i) & 1) << shift))
+}
+
+// Move bit `shifts[i]` of `bits` to bit `i`
+fn gather_bits(bits: u32, shifts: &[u8]) -> u32 {
+    shifts
+        .iter()
+        .enumerate()
+        .fold(0, |value, (i, &shift)| value | (((bits >> shift) & 1) << i))
+}
+
+// The output write registers a `ParallelPort` write goes through, apart
+// so that the sequence can be checked against a model of the PIO
+trait OutputDataRegisters {
+    fn enable_output_writes(&self, mask: u32);
+    fn disable_output_writes(&self, mask: u32);
+    fn write_output_data(&self, bits: u32);
+}
+
+impl OutputDataRegisters for pioa::RegisterBlock {
+    fn enable_output_writes(&self, mask: u32) {
+        unsafe { self.ower.write_with_zero(|w| w.bits(mask)) }
+    }
+
+    fn disable_output_writes(&self, mask: u32) {
+        unsafe { self.owdr.write_with_zero(|w| w.bits(mask)) }
+    }
+
+    fn write_output_data(&self, bits: u32) {
+        unsafe { self.odsr.write_with_zero(|w| w.bits(bits)) }
+    }
+}
+
+// OWSR is shared by all the ports of a group, so it only holds a port's
+// mask for the duration of that port's ODSR store.  Called within a
+// critical section, so that no other write can come in between.
+fn write_masked<R: OutputDataRegisters>(regs: &R, mask: u32, bits: u32) {
+    regs.enable_output_writes(mask);
+    regs.write_output_data(bits);
+    regs.disable_output_writes(mask);
+}
+
+/// Up to 32 pins of the same PIO group, read or written together as one
+/// value in a single register access, so that all the pins change at once.
+/// Bit `i` of the value maps to the `i`th pin given to `new()`.  Several
+/// ports can share a group, each write leaving the other pins as they are.
+pub struct ParallelPort<MODE, const N: usize> {
+    pins: [AnyPin<MODE>; N],
+    shifts: [u8; N],
+    mask: u32,
+}
+
+impl<MODE, const N: usize> ParallelPort<MODE, N> {
+    // Evaluated by `new()`, so that a port of no pins or of more pins than a
+    // PIO group has fails to build
+    const VALID_WIDTH: () = assert!(N > 0 && N <= 32);
+
+    /// Group `pins`, which must all belong to the same PIO group.  On
+    /// error, the pins are handed back.
+    pub fn new(pins: [AnyPin<MODE>; N]) -> Result<Self, (ParallelPortError, [AnyPin<MODE>; N])> {
+        let () = Self::VALID_WIDTH;
+        let mut shifts = [0; N];
+        let mut mask = 0;
+        for (i, pin) in pins.iter().enumerate() {
+            if pin.regs != pins[0].regs {
+                return Err((ParallelPortError::MixedGroups, pins));
+            }
+            if mask & pin.mask != 0 {
+                return Err((ParallelPortError::DuplicatePin, pins));
             }
+            mask |= pin.mask;
+            shifts[i] = pin.mask.trailing_zeros() as u8;
         }
+        Ok(Self { pins, shifts, mask })
+    }
+
+    /// Release the pins.
+    pub fn free(self) -> [AnyPin<MODE>; N] {
+        self.pins
+    }
 
-        impl<MODE> InputPin for $PinType<MODE> {
-            type Error = core::convert::Infallible;
+    /// Bit mask of the port's pins within their PIO controller's registers
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
 
-            fn try_is_high(&self) -> Result<bool, Self::Error> {
-                Ok(self.is_high())
-            }
+    /// Read the logic levels of all the pins at once.
+    pub fn read(&self) -> u32 {
+        let bits = unsafe { (*self.pins[0].regs).pdsr.read().bits() };
+        gather_bits(bits, &self.shifts)
+    }
+}
+
+impl<MODE, const N: usize> ParallelPort<Output<MODE>, N> {
+    /// Set the logic levels of all the pins at once.  Bits beyond the
+    /// port's width are ignored.
+    pub fn write(&mut self, value: u32) {
+        let bits = scatter_bits(value, &self.shifts);
+        let regs = unsafe { &*self.pins[0].regs };
+        cortex_m::interrupt::free(|_| write_masked(regs, self.mask, bits));
+    }
+}
+
+/// Represents IO pin `N` of the PIO controller named by `PORT` (`'A'` to
+/// `'F'`), in the given MODE.  Each pin of the selected device also has a
+/// type alias of the form `Pa8<MODE>`, and is obtained from its `PioGroup`.
+pub struct Pin<const PORT: char, const N: u8, MODE> {
+    _mode: PhantomData<MODE>,
+}
+
+// Registers of the PIO controller named `port`.  Pins can only be obtained
+// from the controllers present on the selected device.
+#[inline(always)]
+fn port_registers(port: char) -> PioRegisters {
+    match port {
+        'A' => PIOA::ptr(),
+        'B' => PIOB::ptr() as PioRegisters,
+        #[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+        'C' => PIOC::ptr() as PioRegisters,
+        #[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+        'D' => PIOD::ptr() as PioRegisters,
+        #[cfg(feature = "sam3x8h")]
+        'E' => PIOE::ptr() as PioRegisters,
+        #[cfg(feature = "sam3x8h")]
+        'F' => PIOF::ptr() as PioRegisters,
+        _ => unreachable!(),
+    }
+}
+
+impl<const PORT: char, const N: u8> Pin<PORT, N, Unconfigured> {
+    // Only `PioGroup` mints pins, so that each has a single owner
+    pub(crate) fn new() -> Self {
+        Self { _mode: PhantomData }
+    }
+}
+
+impl<const PORT: char, const N: u8, MODE> Pin<PORT, N, MODE> {
+    /// Bit mask of this pin within its PIO controller's registers
+    pub const MASK: u32 = 1 << N;
+
+    fn pio() -> &'static pioa::RegisterBlock {
+        unsafe { &*port_registers(PORT) }
+    }
+
+    fn into_mode<NEW>(self, mode: DynPinMode) -> Pin<PORT, N, NEW> {
+        configure_pins(port_registers(PORT), Self::MASK, mode);
+        Pin { _mode: PhantomData }
+    }
+
+    /// Configures the pin to operate as a floating input
+    pub fn into_floating_input(self) -> Pin<PORT, N, Input<Floating>> {
+        self.into_mode(DynPinMode::FloatingInput)
+    }
+
+    /// Configures the pin to operate as a pulled-up input
+    pub fn into_pull_up_input(self) -> Pin<PORT, N, Input<PullUp>> {
+        self.into_mode(DynPinMode::PullUpInput)
+    }
+
+    /// Configures the pin to operate as an open-drain output
+    pub fn into_open_drain_output(self) -> Pin<PORT, N, Output<OpenDrain>> {
+        self.into_mode(DynPinMode::OpenDrainOutput)
+    }
+
+    /// Configures the pin to operate as an push-pull output
+    pub fn into_push_pull_output(self) -> Pin<PORT, N, Output<PushPull>> {
+        self.into_mode(DynPinMode::PushPullOutput)
+    }
 
-            fn try_is_low(&self) -> Result<bool, Self::Error> {
-                Ok(self.is_low())
+    /// Configures the pin to function as the primary (A) attached peripheral.
+    pub fn into_peripheral_a(self) -> Pin<PORT, N, PfA> {
+        self.into_mode(DynPinMode::PeripheralA)
+    }
+
+    /// Configures the pin to function as the alternate (B) attached peripheral.
+    pub fn into_peripheral_b(self) -> Pin<PORT, N, PfB> {
+        self.into_mode(DynPinMode::PeripheralB)
+    }
+
+    /// Test the logic level of the pin; if it is currently high, return true.
+    /// If the PIO controller is not currently clocked, this will return the
+    /// value from when it was last clocked.
+    pub fn is_high(&self) -> bool {
+        Self::pio().pdsr.read().bits() & Self::MASK != 0
+    }
+
+    /// Test the logic level of the pin; if it is currently low, return true.
+    /// If the PIO controller is not currently clocked, this will return the
+    /// value from when it was last clocked.
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    /// Erase the pin's identity from its type, so it can be stored
+    /// along with other pins of the same mode.
+    pub fn degrade(self) -> AnyPin<MODE> {
+        AnyPin {
+            regs: port_registers(PORT),
+            mask: Self::MASK,
+            _mode: PhantomData,
+        }
+    }
+
+    /// Erase the pin's identity and mode from its type, tracking the
+    /// mode at runtime instead.
+    pub fn into_dyn(self) -> DynPin
+    where
+        MODE: PinMode,
+    {
+        self.degrade().into_dyn()
+    }
+}
+
+impl<const PORT: char, const N: u8, MODE> Pin<PORT, N, Output<MODE>> {
+    /// Toggle the logic level of the pin; if it is currently high, set it low
+    /// and vice-versa.
+    pub fn toggle(&mut self) {
+        if self.is_high() {
+            self.set_low();
+        } else {
+            self.set_high();
+        }
+    }
+
+    /// Set the logic level of the pin high.
+    pub fn set_high(&mut self) {
+        unsafe { Self::pio().sodr.write_with_zero(|w| w.bits(Self::MASK)) }
+    }
+
+    /// Set the logic level of the pin low.
+    pub fn set_low(&mut self) {
+        unsafe { Self::pio().codr.write_with_zero(|w| w.bits(Self::MASK)) }
+    }
+}
+
+impl<const PORT: char, const N: u8, MODE> ToggleableOutputPin for Pin<PORT, N, Output<MODE>> {
+    type Error = core::convert::Infallible;
+
+    fn try_toggle(&mut self) -> Result<(), Self::Error> {
+        self.toggle();
+        Ok(())
+    }
+}
+
+impl<const PORT: char, const N: u8, MODE> OutputPin for Pin<PORT, N, Output<MODE>> {
+    type Error = core::convert::Infallible;
+
+    fn try_set_high(&mut self) -> Result<(), Self::Error> {
+        self.set_high();
+        Ok(())
+    }
+
+    fn try_set_low(&mut self) -> Result<(), Self::Error> {
+        self.set_low();
+        Ok(())
+    }
+}
+
+impl<const PORT: char, const N: u8, MODE> StatefulOutputPin for Pin<PORT, N, Output<MODE>> {
+    fn try_is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_high())
+    }
+
+    fn try_is_set_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_low())
+    }
+}
+
+impl<const PORT: char, const N: u8, MODE, FILTER> Pin<PORT, N, Input<MODE, FILTER>> {
+    /// Sample the input without filtering.
+    pub fn into_unfiltered(self) -> Pin<PORT, N, Input<MODE, Unfiltered>> {
+        unsafe { Self::pio().ifdr.write_with_zero(|w| w.bits(Self::MASK)) }
+        Pin { _mode: PhantomData }
+    }
+
+    /// Filter out pulses shorter than half a master clock cycle.
+    pub fn into_glitch_filtered(self) -> Pin<PORT, N, Input<MODE, GlitchFiltered>> {
+        let pio = Self::pio();
+        unsafe {
+            // Select the master clock glitch filter
+            pio.scifsr.write_with_zero(|w| w.bits(Self::MASK));
+            // Enable the input filter
+            pio.ifer.write_with_zero(|w| w.bits(Self::MASK));
+        }
+        Pin { _mode: PhantomData }
+    }
+
+    /// Filter out pulses shorter than the debounce period, set for the
+    /// whole PIO group with `PioGroup::set_debounce_period()`.
+    pub fn into_debounced(self) -> Pin<PORT, N, Input<MODE, Debounced>> {
+        let pio = Self::pio();
+        unsafe {
+            // Select the slow clock debouncing filter
+            pio.difsr.write_with_zero(|w| w.bits(Self::MASK));
+            // Enable the input filter
+            pio.ifer.write_with_zero(|w| w.bits(Self::MASK));
+        }
+        Pin { _mode: PhantomData }
+    }
+
+    /// Raise the PIO controller's interrupt on `event`.  The
+    /// controller's interrupt must also be unmasked in the NVIC.
+    pub fn enable_interrupt(&mut self, event: InterruptEvent) {
+        let pio = Self::pio();
+        let mask = Self::MASK;
+        unsafe {
+            match event {
+                InterruptEvent::BothEdges => {
+                    // Without additional modes, any edge is detected
+                    pio.aimdr.write_with_zero(|w| w.bits(mask));
+                }
+                InterruptEvent::RisingEdge | InterruptEvent::FallingEdge => {
+                    pio.aimer.write_with_zero(|w| w.bits(mask));
+                    pio.esr.write_with_zero(|w| w.bits(mask));
+                }
+                InterruptEvent::HighLevel | InterruptEvent::LowLevel => {
+                    pio.aimer.write_with_zero(|w| w.bits(mask));
+                    pio.lsr.write_with_zero(|w| w.bits(mask));
+                }
+            }
+            match event {
+                InterruptEvent::RisingEdge | InterruptEvent::HighLevel => {
+                    pio.rehlsr.write_with_zero(|w| w.bits(mask));
+                }
+                InterruptEvent::FallingEdge | InterruptEvent::LowLevel => {
+                    pio.fellsr.write_with_zero(|w| w.bits(mask));
+                }
+                InterruptEvent::BothEdges => (),
             }
+            pio.ier.write_with_zero(|w| w.bits(mask));
         }
-    };
-} // End `pin` macro definition
+    }
+
+    /// Stop raising the PIO controller's interrupt for this pin.
+    pub fn disable_interrupt(&mut self) {
+        unsafe { Self::pio().idr.write_with_zero(|w| w.bits(Self::MASK)) }
+    }
+}
+
+impl<const PORT: char, const N: u8, MODE> InputPin for Pin<PORT, N, MODE> {
+    type Error = core::convert::Infallible;
+
+    fn try_is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_high())
+    }
+
+    fn try_is_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.is_low())
+    }
+}
+
+impl<const PORT: char, const N: u8, MODE> crate::mux::sealed::Sealed for Pin<PORT, N, MODE> {}
+
+impl<const PORT: char, const N: u8, MODE: PinMode> From<Pin<PORT, N, MODE>> for DynPin {
+    fn from(pin: Pin<PORT, N, MODE>) -> Self {
+        pin.into_dyn()
+    }
+}
 
 macro_rules! pio_group {
     (
         $group_id:ident,
+        $port:literal,
         $peripheral_id:expr,
         [
           $($pin_no:expr,)+
@@ -300,31 +980,66 @@ impl PioGroup<[<PIO $group_id:upper>]> {
     }
 
     /// Instantiate a representation of a PIO group, providing an interface
-    /// to all the pins it controls.
-    pub fn new(group: [<PIO $group_id:upper>]) -> Self {
+    /// to all the pins it controls.  The controller's clock must run for
+    /// inputs to be read, filtered, or to raise interrupts.
+    pub fn new(group: [<PIO $group_id:upper>], clock: PeripheralClock<[<PIO $group_id:upper>]>) -> Self {
         Self {
             group,
+            taken: 0,
+            _clock: clock,
+        }
+    }
+
+    /// Set the debounce period of the group's `Debounced` input pins.  The
+    /// period is counted on the slow clock, so it has to be set again if
+    /// the slow clock source changes.
+    pub fn set_debounce_period(&mut self, period: Miliseconds, clocks: &Clocks) {
+        let div = debounce_divider(period, clocks.slck());
+        self.scdr.write(|w| unsafe { w.div().bits(div) });
+    }
+
+    /// Return the pins whose configuration is locked until the next reset,
+    /// one bit per pin, as reported by LOCKSR.
+    pub fn locked_pins(&self) -> u32 {
+        self.locksr.read().bits()
+    }
+
+    /// Read and clear the interrupts pending on this controller, limited
+    /// to the pins with interrupts enabled.  Reading clears every pin's
+    /// flag at once, so the interrupt handler should call this once, and
+    /// hand the result to a `PioHandlers` or `PioInterruptFlags`.
+    pub fn pending_interrupts(&self) -> PioInterrupts {
+        PioInterrupts {
+            bits: self.isr.read().bits() & self.imr.read().bits(),
         }
     }
 
     $(
-    /// Access the configuration and state of the pin of this name on this PIO
-    /// group.
-    pub fn [<p $pin_no>](&self) -> [<Unconfigured P $group_id:lower $pin_no>] {
-        [<Unconfigured P $group_id:lower $pin_no>]::new()
+    /// Take the pin of this name on this PIO group, to configure and use it.
+    /// Each pin is only handed out once; `None` is returned afterwards.
+    pub fn [<p $pin_no>](&mut self) -> Option<[<Unconfigured P $group_id:lower $pin_no>]> {
+        let mask = [<Unconfigured P $group_id:lower $pin_no>]::MASK;
+        if self.taken & mask != 0 {
+            return None;
+        }
+        self.taken |= mask;
+        Some([<Unconfigured P $group_id:lower $pin_no>]::new())
     }
     )+
 
 }
 
-impl From<[<PIO $group_id:upper>]> for PioGroup<[<PIO $group_id:upper>]> {
-    fn from(group: [<PIO $group_id:upper>]) -> Self {
-        Self::new(group)
+impl From<([<PIO $group_id:upper>], PeripheralClock<[<PIO $group_id:upper>]>)> for PioGroup<[<PIO $group_id:upper>]> {
+    fn from(parts: ([<PIO $group_id:upper>], PeripheralClock<[<PIO $group_id:upper>]>)) -> Self {
+        Self::new(parts.0, parts.1)
     }
 }
 
 $(
-    pin!([<PIO $group_id:upper>], [<P $group_id:lower $pin_no>], [<p $group_id:lower $pin_no>], $pin_no);
+/// Represents the IO pin with the matching name.
+pub type [<P $group_id:lower $pin_no>]<MODE> = Pin<$port, $pin_no, MODE>;
+/// Represents an unconfigured IO pin.
+pub type [<Unconfigured P $group_id:lower $pin_no>] = [<P $group_id:lower $pin_no>]<Unconfigured>;
 )+
 } // end paste
     };
@@ -333,6 +1048,7 @@ $(
 // PIOA has the same pin set among all targets
 pio_group!(
     a,
+    'A',
     11,
     [
         0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
@@ -343,6 +1059,7 @@ pio_group!(
 // PIOB has the same pin set among all targets
 pio_group!(
     b,
+    'B',
     12,
     [
         0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
@@ -354,6 +1071,7 @@ pio_group!(
 #[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
 pio_group!(
     c,
+    'C',
     13,
     [
         0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
@@ -364,12 +1082,13 @@ pio_group!(
 // PIOD is not supported by the atsam3x?c targets, and only has pins 0-10 on
 // the atsam3x?e targets
 #[cfg(feature = "sam3_e")]
-pio_group!(d, 14, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10,]);
+pio_group!(d, 'D', 14, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10,]);
 
 // PIOD has pins 0-30 on the atsam3x8h target
 #[cfg(feature = "sam3x8h")]
 pio_group!(
     d,
+    'D',
     14,
     [
         0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
@@ -381,6 +1100,7 @@ pio_group!(
 #[cfg(feature = "sam3x8h")]
 pio_group!(
     e,
+    'E',
     15,
     [
         0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
@@ -390,19 +1110,39 @@ pio_group!(
 
 // PIOF has pins 0-6 on the atsam3x8h target
 #[cfg(feature = "sam3x8h")]
-pio_group!(f, 16, [0, 1, 2, 3, 4, 5, 6,]);
+pio_group!(f, 'F', 16, [0, 1, 2, 3, 4, 5, 6,]);
 
 /// This macro is a helper for defining a `Pins` type in a board support
 /// crate.  This type is used to provide more meaningful aliases for the
 /// various GPIO pins for a given board.
+///
+/// Each pin is owned by a single field, and assigning the same pin to two
+/// fields fails to compile.  A pin known by several names on the board
+/// lists the others after `alias`.  Each alias defines a type alias in
+/// CamelCase for the pin, so that `LedL<MODE>` names the type of the pin
+/// owned by `pins.d13_tiob0`.
 #[macro_export]
 macro_rules! define_pins {
     (
         $(#[$topattr:meta])* struct $Type:ident,
-        $( $(#[$attr:meta])* pin $name:ident = $pio_group:ident : $pin_ident:expr),+ ,
+        $( $(#[$attr:meta])* pin $name:ident = $pio_group:ident : $pin_ident:literal $(alias $alias:ident)* ),+ ,
     ) => {
 
 $crate::paste! {
+// Each pin can only be owned by one field; a pin assigned twice fails here
+// with its name "defined multiple times"
+const _: () = {
+    $(
+    #[allow(non_upper_case_globals, dead_code)]
+    const [<P $pio_group $pin_ident>]: () = ();
+    )+
+};
+
+$($(
+#[doc = concat!("Alias of the type of the `", stringify!($name), "` pin")]
+pub type [<$alias:camel>]<MODE> = $crate::gpio::[<P $pio_group $pin_ident>]<MODE>;
+)*)+
+
 $(#[$topattr])*
 pub struct $Type {
     /// Opaque reference to PIO controller A
@@ -432,12 +1172,15 @@ pub struct $Type {
 impl $Type {
     $crate::paste! {
     #[cfg(feature = "sam3_c")]
-    /// Returns the pins for the device
-    pub fn new(pioa: $crate::target_device::PIOA, piob: $crate::target_device::PIOB) -> Self {
-        let pa = $crate::gpio::PioGroup::from(pioa);
-        let pb = $crate::gpio::PioGroup::from(piob);
+    /// Returns the pins for the device, enabling the clocks of the PIO
+    /// controllers.  Panics if a clock token for one of them has already
+    /// been handed out.
+    #[allow(unused_mut)]
+    pub fn new(pioa: $crate::target_device::PIOA, piob: $crate::target_device::PIOB, sysclk: &mut $crate::clock::SystemClocks) -> Self {
+        let mut pa = $crate::gpio::PioGroup::from((pioa, sysclk.enable_peripheral_clock().unwrap()));
+        let mut pb = $crate::gpio::PioGroup::from((piob, sysclk.enable_peripheral_clock().unwrap()));
         $(
-        let $name = [<p $pio_group>].[<p $pin_ident>]();
+        let $name = [<p $pio_group>].[<p $pin_ident>]().unwrap();
         )+
         $Type {
             pa,
@@ -449,14 +1192,17 @@ impl $Type {
 
     $crate::paste! {
     #[cfg(feature = "sam3_e")]
-    /// Returns the pins for the device
-    pub fn new(pioa: $crate::target_device::PIOA, piob: $crate::target_device::PIOB, pioc: $crate::target_device::PIOC, piod: $crate::target_device::PIOD) -> Self {
-        let pa = $crate::gpio::PioGroup::from(pioa);
-        let pb = $crate::gpio::PioGroup::from(piob);
-        let pc = $crate::gpio::PioGroup::from(pioc);
-        let pd = $crate::gpio::PioGroup::from(piod);
+    /// Returns the pins for the device, enabling the clocks of the PIO
+    /// controllers.  Panics if a clock token for one of them has already
+    /// been handed out.
+    #[allow(unused_mut)]
+    pub fn new(pioa: $crate::target_device::PIOA, piob: $crate::target_device::PIOB, pioc: $crate::target_device::PIOC, piod: $crate::target_device::PIOD, sysclk: &mut $crate::clock::SystemClocks) -> Self {
+        let mut pa = $crate::gpio::PioGroup::from((pioa, sysclk.enable_peripheral_clock().unwrap()));
+        let mut pb = $crate::gpio::PioGroup::from((piob, sysclk.enable_peripheral_clock().unwrap()));
+        let mut pc = $crate::gpio::PioGroup::from((pioc, sysclk.enable_peripheral_clock().unwrap()));
+        let mut pd = $crate::gpio::PioGroup::from((piod, sysclk.enable_peripheral_clock().unwrap()));
         $(
-        let $name = [<p $pio_group>].[<p $pin_ident>]();
+        let $name = [<p $pio_group>].[<p $pin_ident>]().unwrap();
         )+
         $Type {
             pa,
@@ -470,16 +1216,19 @@ impl $Type {
 
     $crate::paste! {
     #[cfg(feature = "sam3x8h")]
-    /// Returns the pins for the device
-    pub fn new(pioa: $crate::target_device::PIOA, piob: $crate::target_device::PIOB, pioc: $crate::target_device::PIOC, piod: $crate::target_device::PIOD,  pioe: $crate::target_device::PIOE, piof: $crate::target_device::PIOF) -> Self {
-        let pa = $crate::gpio::PioGroup::from(pioa);
-        let pb = $crate::gpio::PioGroup::from(piob);
-        let pc = $crate::gpio::PioGroup::from(pioc);
-        let pd = $crate::gpio::PioGroup::from(piod);
-        let pe = $crate::gpio::PioGroup::from(pioe);
-        let pf = $crate::gpio::PioGroup::from(piof);
+    /// Returns the pins for the device, enabling the clocks of the PIO
+    /// controllers.  Panics if a clock token for one of them has already
+    /// been handed out.
+    #[allow(unused_mut)]
+    pub fn new(pioa: $crate::target_device::PIOA, piob: $crate::target_device::PIOB, pioc: $crate::target_device::PIOC, piod: $crate::target_device::PIOD,  pioe: $crate::target_device::PIOE, piof: $crate::target_device::PIOF, sysclk: &mut $crate::clock::SystemClocks) -> Self {
+        let mut pa = $crate::gpio::PioGroup::from((pioa, sysclk.enable_peripheral_clock().unwrap()));
+        let mut pb = $crate::gpio::PioGroup::from((piob, sysclk.enable_peripheral_clock().unwrap()));
+        let mut pc = $crate::gpio::PioGroup::from((pioc, sysclk.enable_peripheral_clock().unwrap()));
+        let mut pd = $crate::gpio::PioGroup::from((piod, sysclk.enable_peripheral_clock().unwrap()));
+        let mut pe = $crate::gpio::PioGroup::from((pioe, sysclk.enable_peripheral_clock().unwrap()));
+        let mut pf = $crate::gpio::PioGroup::from((piof, sysclk.enable_peripheral_clock().unwrap()));
         $(
-        let $name = [<p $pio_group>].[<p $pin_ident>]();
+        let $name = [<p $pio_group>].[<p $pin_ident>]().unwrap();
         )+
         $Type {
             pa,
@@ -495,3 +1244,88 @@ impl $Type {
 }
 
 }}
+
+#[cfg(test)]
+mod tests {
+    use crate::gpio::*;
+
+    #[test]
+    fn interrupt_flags_keep_events_until_taken() {
+        let flags = PioInterruptFlags::new();
+        flags.record(PioInterrupts { bits: 1 << 3 });
+        flags.record(PioInterrupts { bits: 1 << 7 });
+        assert!(flags.take(1 << 7));
+        assert!(!flags.take(1 << 7));
+        assert!(flags.take(1 << 3));
+    }
+
+    #[test]
+    fn debounce_divider_covers_period() {
+        // 2 * (DIV + 1) slow clock cycles per sample
+        assert_eq!(debounce_divider(Miliseconds(0), Hertz(32_768)), 0);
+        assert_eq!(debounce_divider(Miliseconds(1), Hertz(32_768)), 16);
+        assert_eq!(debounce_divider(Miliseconds(10), Hertz(32_768)), 163);
+        assert_eq!(debounce_divider(Miliseconds(20), Hertz(32_000)), 319);
+        assert_eq!(debounce_divider(Miliseconds(2000), Hertz(32_768)), 0x3fff);
+    }
+
+    #[test]
+    fn parallel_port_bits_follow_pin_order() {
+        // PC1..PC8, as used for a data bus
+        let shifts = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(scatter_bits(0xa5, &shifts), 0xa5 << 1);
+        assert_eq!(gather_bits(0xa5 << 1, &shifts), 0xa5);
+        // Out of order pins, with bits beyond the width ignored
+        let shifts = [31, 0, 12, 7];
+        assert_eq!(scatter_bits(0xf3, &shifts), (1 << 31) | 1);
+        assert_eq!(gather_bits(!0, &shifts), 0xf);
+        assert_eq!(gather_bits(1 << 12, &shifts), 0b100);
+    }
+
+    // ODSR and OWSR as the PIO updates them
+    #[derive(Default)]
+    struct OutputModel {
+        owsr: core::cell::Cell<u32>,
+        odsr: core::cell::Cell<u32>,
+    }
+
+    impl OutputDataRegisters for OutputModel {
+        fn enable_output_writes(&self, mask: u32) {
+            self.owsr.set(self.owsr.get() | mask);
+        }
+
+        fn disable_output_writes(&self, mask: u32) {
+            self.owsr.set(self.owsr.get() & !mask);
+        }
+
+        fn write_output_data(&self, bits: u32) {
+            let owsr = self.owsr.get();
+            self.odsr.set((self.odsr.get() & !owsr) | (bits & owsr));
+        }
+    }
+
+    #[test]
+    fn parallel_ports_share_a_group() {
+        // PC1..PC8 and PC12..PC15 as two ports
+        let pio = OutputModel::default();
+        let (bus, control) = (0x1fe, 0xf000);
+        write_masked(&pio, bus, 0xa5 << 1);
+        write_masked(&pio, control, !0);
+        assert_eq!(pio.odsr.get(), (0xa5 << 1) | control);
+        write_masked(&pio, bus, 0);
+        assert_eq!(pio.odsr.get(), control);
+        assert_eq!(pio.owsr.get(), 0);
+    }
+
+    #[test]
+    fn pending_pins_in_order() {
+        let pending = PioInterrupts {
+            bits: (1 << 31) | (1 << 4) | 1,
+        };
+        let mut pins = pending.pins();
+        assert_eq!(pins.next(), Some(0));
+        assert_eq!(pins.next(), Some(4));
+        assert_eq!(pins.next(), Some(31));
+        assert_eq!(pins.next(), None);
+    }
+}
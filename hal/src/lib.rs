@@ -52,6 +52,10 @@ pub mod comm;
 pub mod delay;
 pub mod flash;
 pub mod gpio;
+pub mod mux;
+pub mod pck;
+pub mod power;
 pub mod prelude;
 pub mod time;
 pub mod watchdog;
+pub mod write_protect;
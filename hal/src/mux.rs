@@ -0,0 +1,292 @@
+//! Mapping peripheral signals to the pins that can carry them
+//!
+//! Each PIO pin can be switched to one of two peripheral functions (A or
+//! B).  The traits in this module are implemented for the pins, in the
+//! matching `PfA`/`PfB` mode, that carry a given signal on the selected
+//! device, so that driver constructors can reject a wrongly muxed pin at
+//! compile time.  The traits are sealed; they can't be implemented outside
+//! of this crate.
+//!
+//! Pins of PIOC and PIOD are only available on the 144 and 217-pin
+//! packages, and pins of PIOE and PIOF only on the 217-pin `sam3x8h`, so
+//! the signals they carry are only mapped on those devices.
+use crate::gpio::*;
+use crate::pck::{Pck0, Pck1, Pck2};
+use crate::target_device::{CAN0, CAN1, SPI0, TC0, TWI0, TWI1, UART, USART0, USART1, USART2};
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+use crate::target_device::{TC2, USART3};
+
+#[cfg(feature = "sam3x8h")]
+use crate::target_device::SPI1;
+
+pub(crate) mod sealed {
+    /// Implemented for every pin type, so only this crate can map signals
+    pub trait Sealed {}
+}
+
+use sealed::Sealed;
+
+/// Channel 0 of a timer counter block or of the PWM controller
+pub struct Ch0;
+/// Channel 1 of a timer counter block or of the PWM controller
+pub struct Ch1;
+/// Channel 2 of a timer counter block or of the PWM controller
+pub struct Ch2;
+/// Channel 3 of the PWM controller
+pub struct Ch3;
+/// Channel 4 of the PWM controller
+pub struct Ch4;
+/// Channel 5 of the PWM controller
+pub struct Ch5;
+/// Channel 6 of the PWM controller
+pub struct Ch6;
+/// Channel 7 of the PWM controller
+pub struct Ch7;
+
+/// ADC channel 0
+pub struct Channel0;
+/// ADC channel 1
+pub struct Channel1;
+/// ADC channel 2
+pub struct Channel2;
+/// ADC channel 3
+pub struct Channel3;
+/// ADC channel 4
+pub struct Channel4;
+/// ADC channel 5
+pub struct Channel5;
+/// ADC channel 6
+pub struct Channel6;
+/// ADC channel 7
+pub struct Channel7;
+/// ADC channel 8
+pub struct Channel8;
+/// ADC channel 9
+pub struct Channel9;
+/// ADC channel 10
+pub struct Channel10;
+/// ADC channel 11
+pub struct Channel11;
+/// ADC channel 12
+pub struct Channel12;
+/// ADC channel 13
+pub struct Channel13;
+/// ADC channel 14
+pub struct Channel14;
+
+/// Pin carrying the receive data input of serial peripheral `P` (UART,
+/// USART or CAN controller).
+pub trait RxPin<P>: Sealed {}
+/// Pin carrying the transmit data output of serial peripheral `P` (UART,
+/// USART or CAN controller).
+pub trait TxPin<P>: Sealed {}
+/// Pin carrying the clock of peripheral `P` (USART or SPI controller).
+pub trait SckPin<P>: Sealed {}
+/// Pin carrying the request-to-send output of USART `P`.
+pub trait RtsPin<P>: Sealed {}
+/// Pin carrying the clear-to-send input of USART `P`.
+pub trait CtsPin<P>: Sealed {}
+/// Pin carrying the master-in slave-out data of SPI controller `P`.
+pub trait MisoPin<P>: Sealed {}
+/// Pin carrying the master-out slave-in data of SPI controller `P`.
+pub trait MosiPin<P>: Sealed {}
+/// Pin carrying chip select 0 of SPI controller `P`, which is the slave
+/// select input in slave mode.
+pub trait NssPin<P>: Sealed {}
+/// Pin carrying chip select 1 of SPI controller `P`.
+pub trait Npcs1Pin<P>: Sealed {}
+/// Pin carrying chip select 2 of SPI controller `P`.
+pub trait Npcs2Pin<P>: Sealed {}
+/// Pin carrying chip select 3 of SPI controller `P`.
+pub trait Npcs3Pin<P>: Sealed {}
+/// Pin carrying the data line of TWI controller `P`.
+pub trait TwdPin<P>: Sealed {}
+/// Pin carrying the clock line of TWI controller `P`.
+pub trait TwckPin<P>: Sealed {}
+/// Pin carrying the TIOA line of channel `CH` of timer counter block `TC`.
+pub trait TioaPin<TC, CH>: Sealed {}
+/// Pin carrying the TIOB line of channel `CH` of timer counter block `TC`.
+pub trait TiobPin<TC, CH>: Sealed {}
+/// Pin carrying the high side output of PWM channel `CH`.
+pub trait PwmhPin<CH>: Sealed {}
+/// Pin carrying the low side output of PWM channel `CH`.
+pub trait PwmlPin<CH>: Sealed {}
+/// Pin carrying analog input `CH` of the ADC.  The ADC takes over the pin
+/// once the channel is enabled; the pin is expected to be a floating
+/// input, so that neither the output driver nor the pull-up disturbs the
+/// measurement.
+pub trait AdPin<CH>: Sealed {}
+/// Pin carrying the output of programmable clock `PCK`.
+pub trait PckPin<PCK>: Sealed {}
+
+macro_rules! signals {
+    ($($Trait:ident<$($P:ty),+> for $Pin:ident<$Mode:ty>;)+) => {
+        $(
+            impl $Trait<$($P),+> for $Pin<$Mode> {}
+        )+
+    };
+}
+
+// Serial signals
+signals! {
+    RxPin<UART> for Pa8<PfA>;
+    TxPin<UART> for Pa9<PfA>;
+
+    RxPin<USART0> for Pa10<PfA>;
+    TxPin<USART0> for Pa11<PfA>;
+    SckPin<USART0> for Pa17<PfB>;
+    RtsPin<USART0> for Pb25<PfA>;
+    CtsPin<USART0> for Pb26<PfA>;
+
+    RxPin<USART1> for Pa12<PfA>;
+    TxPin<USART1> for Pa13<PfA>;
+    SckPin<USART1> for Pa16<PfA>;
+    RtsPin<USART1> for Pa14<PfA>;
+    CtsPin<USART1> for Pa15<PfA>;
+
+    RxPin<USART2> for Pb21<PfA>;
+    TxPin<USART2> for Pb20<PfA>;
+    SckPin<USART2> for Pb24<PfA>;
+    RtsPin<USART2> for Pb22<PfA>;
+    CtsPin<USART2> for Pb23<PfA>;
+
+    RxPin<CAN0> for Pa1<PfA>;
+    TxPin<CAN0> for Pa0<PfA>;
+    RxPin<CAN1> for Pb15<PfA>;
+    TxPin<CAN1> for Pb14<PfA>;
+}
+
+// USART3 data lines are on PIOD
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+signals! {
+    RxPin<USART3> for Pd5<PfB>;
+    TxPin<USART3> for Pd4<PfB>;
+}
+
+// USART3 clock and handshake lines are on PIOE and PIOF
+#[cfg(feature = "sam3x8h")]
+signals! {
+    SckPin<USART3> for Pe16<PfB>;
+    RtsPin<USART3> for Pf5<PfA>;
+    CtsPin<USART3> for Pf4<PfA>;
+}
+
+// SPI signals
+signals! {
+    MisoPin<SPI0> for Pa25<PfA>;
+    MosiPin<SPI0> for Pa26<PfA>;
+    SckPin<SPI0> for Pa27<PfA>;
+    NssPin<SPI0> for Pa28<PfA>;
+    Npcs1Pin<SPI0> for Pa29<PfA>;
+    Npcs1Pin<SPI0> for Pb20<PfB>;
+    Npcs2Pin<SPI0> for Pa30<PfA>;
+    Npcs2Pin<SPI0> for Pb21<PfB>;
+    Npcs3Pin<SPI0> for Pa31<PfA>;
+    Npcs3Pin<SPI0> for Pb23<PfB>;
+}
+
+#[cfg(feature = "sam3x8h")]
+signals! {
+    MisoPin<SPI1> for Pe28<PfA>;
+    MosiPin<SPI1> for Pe29<PfA>;
+    SckPin<SPI1> for Pe30<PfA>;
+    NssPin<SPI1> for Pe31<PfA>;
+}
+
+// TWI signals
+signals! {
+    TwdPin<TWI0> for Pa17<PfA>;
+    TwckPin<TWI0> for Pa18<PfA>;
+    TwdPin<TWI1> for Pb12<PfA>;
+    TwckPin<TWI1> for Pb13<PfA>;
+}
+
+// Timer counter signals
+signals! {
+    TioaPin<TC0, Ch0> for Pb25<PfB>;
+    TiobPin<TC0, Ch0> for Pb27<PfB>;
+    TioaPin<TC0, Ch1> for Pa2<PfA>;
+    TiobPin<TC0, Ch1> for Pa3<PfA>;
+    TioaPin<TC0, Ch2> for Pa5<PfA>;
+    TiobPin<TC0, Ch2> for Pa6<PfA>;
+}
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+signals! {
+    TioaPin<TC2, Ch0> for Pc25<PfB>;
+    TiobPin<TC2, Ch0> for Pc26<PfB>;
+    TioaPin<TC2, Ch1> for Pc28<PfB>;
+    TiobPin<TC2, Ch1> for Pc29<PfB>;
+    TioaPin<TC2, Ch2> for Pd7<PfB>;
+    TiobPin<TC2, Ch2> for Pd8<PfB>;
+}
+
+// PWM signals
+signals! {
+    PwmlPin<Ch3> for Pa0<PfB>;
+    PwmhPin<Ch0> for Pa8<PfB>;
+    PwmhPin<Ch3> for Pa9<PfB>;
+    PwmlPin<Ch1> for Pa12<PfB>;
+    PwmhPin<Ch2> for Pa13<PfB>;
+    PwmhPin<Ch1> for Pa19<PfB>;
+    PwmlPin<Ch2> for Pa20<PfB>;
+    PwmlPin<Ch0> for Pa21<PfB>;
+
+    PwmhPin<Ch0> for Pb12<PfB>;
+    PwmhPin<Ch1> for Pb13<PfB>;
+    PwmhPin<Ch2> for Pb14<PfB>;
+    PwmhPin<Ch3> for Pb15<PfB>;
+    PwmlPin<Ch0> for Pb16<PfB>;
+    PwmlPin<Ch1> for Pb17<PfB>;
+    PwmlPin<Ch2> for Pb18<PfB>;
+    PwmlPin<Ch3> for Pb19<PfB>;
+}
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+signals! {
+    PwmlPin<Ch0> for Pc2<PfB>;
+    PwmhPin<Ch0> for Pc3<PfB>;
+    PwmlPin<Ch1> for Pc4<PfB>;
+    PwmhPin<Ch1> for Pc5<PfB>;
+    PwmlPin<Ch2> for Pc6<PfB>;
+    PwmhPin<Ch2> for Pc7<PfB>;
+    PwmlPin<Ch3> for Pc8<PfB>;
+    PwmhPin<Ch3> for Pc9<PfB>;
+    PwmhPin<Ch6> for Pc18<PfB>;
+    PwmhPin<Ch5> for Pc19<PfB>;
+    PwmhPin<Ch4> for Pc20<PfB>;
+    PwmlPin<Ch4> for Pc21<PfB>;
+    PwmlPin<Ch5> for Pc22<PfB>;
+    PwmlPin<Ch6> for Pc23<PfB>;
+    PwmlPin<Ch7> for Pc24<PfB>;
+}
+
+// Programmable clock outputs
+signals! {
+    PckPin<Pck0> for Pa1<PfB>;
+    PckPin<Pck0> for Pb22<PfB>;
+    PckPin<Pck1> for Pa24<PfB>;
+    PckPin<Pck1> for Pa30<PfB>;
+    PckPin<Pck2> for Pa28<PfB>;
+    PckPin<Pck2> for Pa31<PfB>;
+}
+
+// ADC inputs
+signals! {
+    AdPin<Channel0> for Pa2<Input<Floating>>;
+    AdPin<Channel1> for Pa3<Input<Floating>>;
+    AdPin<Channel2> for Pa4<Input<Floating>>;
+    AdPin<Channel3> for Pa6<Input<Floating>>;
+    AdPin<Channel4> for Pa22<Input<Floating>>;
+    AdPin<Channel5> for Pa23<Input<Floating>>;
+    AdPin<Channel6> for Pa24<Input<Floating>>;
+    AdPin<Channel7> for Pa16<Input<Floating>>;
+    AdPin<Channel8> for Pb12<Input<Floating>>;
+    AdPin<Channel9> for Pb13<Input<Floating>>;
+    AdPin<Channel10> for Pb17<Input<Floating>>;
+    AdPin<Channel11> for Pb18<Input<Floating>>;
+    AdPin<Channel12> for Pb19<Input<Floating>>;
+    AdPin<Channel13> for Pb20<Input<Floating>>;
+    AdPin<Channel14> for Pb21<Input<Floating>>;
+}
@@ -0,0 +1,129 @@
+//! Routing clocks out to the programmable clock output pins (PCK0-PCK2).
+//!
+//! Each programmable clock selects one of the clock tree's clocks as its
+//! source, divides it down by a power of two, and drives it out on a pin
+//! muxed to the matching PCKx peripheral function.  This is typically used
+//! to clock external devices such as audio codecs or FPGAs.
+//!
+//! | clock | pins            |
+//! | ----- | --------------- |
+//! | PCK0  | PA01/B, PB22/B  |
+//! | PCK1  | PA24/B, PA30/B  |
+//! | PCK2  | PA28/B, PA31/B  |
+use crate::clock::{ClockError, Clocks, SystemClocks, CLOCK_TIMEOUT_SLCK};
+use crate::mux::PckPin;
+use crate::target_device;
+use crate::time::Hertz;
+use core::marker::PhantomData;
+use target_device::generic::Variant;
+
+/// Valid clock sources for a programmable clock
+pub type PckSource = target_device::pmc::pmc_pck::CSS_A;
+/// Valid prescaler values for a programmable clock
+pub type PckPrescaler = target_device::pmc::pmc_pck::PRES_A;
+
+/// Programmable clock 0
+pub struct Pck0;
+/// Programmable clock 1
+pub struct Pck1;
+/// Programmable clock 2
+pub struct Pck2;
+
+/// A programmable clock, driving its output pin.
+pub struct ProgrammableClock<PCK, PIN> {
+    pin: PIN,
+    _pck: PhantomData<PCK>,
+}
+
+macro_rules! programmable_clock {
+    ($Pck:ident, $n:expr) => {
+        crate::paste! {
+        impl<PIN: PckPin<$Pck>> ProgrammableClock<$Pck, PIN> {
+            /// Configure the programmable clock to run from `source` divided
+            /// by `prescaler`, and start driving it out on `pin`.
+            pub fn new(
+                sysclk: &mut SystemClocks,
+                pin: PIN,
+                source: PckSource,
+                prescaler: PckPrescaler,
+            ) -> Result<Self, ClockError> {
+                let mut pck = Self {
+                    pin,
+                    _pck: PhantomData,
+                };
+                pck.set_source(sysclk, source, prescaler)?;
+                Ok(pck)
+            }
+
+            /// Change the source and prescaler of the programmable clock.
+            /// The output is stopped while the change is made, and restarted
+            /// once the new clock is ready.
+            pub fn set_source(
+                &mut self,
+                sysclk: &mut SystemClocks,
+                source: PckSource,
+                prescaler: PckPrescaler,
+            ) -> Result<(), ClockError> {
+                self.disable(sysclk);
+                sysclk.pmc_pck[$n]
+                    .write_with_zero(|w| w.css().variant(source).pres().variant(prescaler));
+                self.enable(sysclk)
+            }
+
+            /// Start driving the clock out on the pin, and wait until it is
+            /// running.  Fails if the clock doesn't report ready in time,
+            /// such as when its source is disabled.
+            pub fn enable(&mut self, sysclk: &mut SystemClocks) -> Result<(), ClockError> {
+                sysclk.pmc_scer.write_with_zero(|w| w.[<pck $n>]().set_bit());
+
+                // Wait until the programmable clock reports ready
+                // 0 = not ready, 1 = ready
+                sysclk.wait_until(
+                    CLOCK_TIMEOUT_SLCK,
+                    ClockError::ProgrammableClockNotReady,
+                    |s| s.pmc_sr.read().[<pckrdy $n>]().bits(),
+                )
+            }
+
+            /// Stop driving the clock out on the pin.
+            pub fn disable(&mut self, sysclk: &mut SystemClocks) {
+                sysclk.pmc_scdr.write_with_zero(|w| w.[<pck $n>]().set_bit());
+            }
+
+            /// Return whether the clock is currently being driven out on the
+            /// pin.
+            pub fn is_enabled(&self, sysclk: &SystemClocks) -> bool {
+                sysclk.pmc_scsr.read().[<pck $n>]().bits()
+            }
+
+            /// Return the frequency of the programmable clock, based on the
+            /// frequencies of the clock tree.
+            pub fn frequency(&self, sysclk: &SystemClocks, clocks: &Clocks) -> Hertz {
+                let pck = sysclk.pmc_pck[$n].read();
+                let source = match pck.css().variant() {
+                    Variant::Val(PckSource::SLOW_CLK) => clocks.slck(),
+                    Variant::Val(PckSource::MAIN_CLK) => clocks.mainck(),
+                    Variant::Val(PckSource::PLLA_CLK) => clocks.pllack(),
+                    Variant::Val(PckSource::UPLL_CLK) => clocks.upllck(),
+                    Variant::Val(PckSource::MCK) => clocks.mck(),
+                    Variant::Res(_) => unreachable!(),
+                };
+                match pck.pres().variant() {
+                    Variant::Val(prescaler) => Hertz(source.0 >> (prescaler as u8)),
+                    Variant::Res(_) => unreachable!(),
+                }
+            }
+
+            /// Stop the programmable clock, and release its output pin.
+            pub fn free(mut self, sysclk: &mut SystemClocks) -> PIN {
+                self.disable(sysclk);
+                self.pin
+            }
+        }
+        } // end paste
+    };
+}
+
+programmable_clock!(Pck0, 0);
+programmable_clock!(Pck1, 1);
+programmable_clock!(Pck2, 2);
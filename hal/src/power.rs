@@ -0,0 +1,197 @@
+//! Low-power modes
+//!
+//! The device supports three low-power modes, trading wake-up time and
+//! retained state for lower consumption:
+//!
+//! | mode   | core clock | retained      | wakes on                          |
+//! | ------ | ---------- | ------------- | --------------------------------- |
+//! | Sleep  | stopped    | everything    | any enabled interrupt             |
+//! | Wait   | stopped    | everything    | WKUP0-15 pins, RTT and RTC alarms |
+//! | Backup | powered off| backup domain | WKUP0-15 pins, RTT and RTC alarms |
+//!
+//! Waking from Backup mode resets the core, so the application restarts
+//! from scratch and has to set up its clocks again.
+use cortex_m::asm;
+use cortex_m::peripheral::SCB;
+
+use crate::clock::{ClockError, Clocks, FastRCFreq, SystemClocks, CLOCK_TIMEOUT_SLCK};
+
+/// Wake-up inputs (WKUPn) of the supply controller.  See the datasheet's
+/// pinout for which PIO pin carries each of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WakeupInput {
+    /// WKUP0
+    Wkup0 = 0,
+    /// WKUP1
+    Wkup1 = 1,
+    /// WKUP2
+    Wkup2 = 2,
+    /// WKUP3
+    Wkup3 = 3,
+    /// WKUP4
+    Wkup4 = 4,
+    /// WKUP5
+    Wkup5 = 5,
+    /// WKUP6
+    Wkup6 = 6,
+    /// WKUP7
+    Wkup7 = 7,
+    /// WKUP8
+    Wkup8 = 8,
+    /// WKUP9
+    Wkup9 = 9,
+    /// WKUP10
+    Wkup10 = 10,
+    /// WKUP11
+    Wkup11 = 11,
+    /// WKUP12
+    Wkup12 = 12,
+    /// WKUP13
+    Wkup13 = 13,
+    /// WKUP14
+    Wkup14 = 14,
+    /// WKUP15
+    Wkup15 = 15,
+}
+
+/// Inputs that wake the device from Wait or Backup mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WakeupSources {
+    /// WKUPn inputs able to wake the device, one bit per input
+    wkup_pins: u16,
+    /// WKUPn inputs that wake the device on a high level rather than a
+    /// low level, one bit per input
+    wkup_active_high: u16,
+    /// Wake on the real-time timer alarm
+    rtt_alarm: bool,
+    /// Wake on the real-time clock alarm
+    rtc_alarm: bool,
+}
+
+impl WakeupSources {
+    /// Instantiate an empty set of wake-up sources.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wake when the given WKUPn input reaches the given level.
+    pub fn wkup_pin(mut self, input: WakeupInput, active_high: bool) -> Self {
+        let mask = 1 << input as u16;
+        self.wkup_pins |= mask;
+        if active_high {
+            self.wkup_active_high |= mask;
+        } else {
+            self.wkup_active_high &= !mask;
+        }
+        self
+    }
+
+    /// Wake when the real-time timer alarm fires.
+    pub fn rtt_alarm(mut self) -> Self {
+        self.rtt_alarm = true;
+        self
+    }
+
+    /// Wake when the real-time clock alarm fires.
+    pub fn rtc_alarm(mut self) -> Self {
+        self.rtc_alarm = true;
+        self
+    }
+}
+
+/// Controller for entering the low-power modes.
+pub struct Power {
+    scb: SCB,
+}
+
+impl Power {
+    /// Take ownership of the system control block, which selects between
+    /// the core's sleep and deep sleep states.
+    pub fn new(scb: SCB) -> Self {
+        Self { scb }
+    }
+
+    /// Releases the system control block
+    pub fn free(self) -> SCB {
+        self.scb
+    }
+
+    /// Enter Sleep mode, stopping the core clock until an interrupt occurs.
+    /// Peripherals keep running, so any enabled interrupt wakes the core.
+    pub fn sleep(&mut self) {
+        self.scb.clear_sleepdeep();
+        asm::wfi();
+    }
+
+    /// Enter Wait mode, stopping all clocks until one of the `wakeup`
+    /// sources triggers.
+    ///
+    /// The master clock has to run from the fast RC oscillator while
+    /// waiting, so the crystal and PLLs are stopped first.  Once awake, the
+    /// previous clock tree is restored, and its frequencies returned.
+    pub fn wait(
+        &mut self,
+        sysclk: &mut SystemClocks,
+        wakeup: WakeupSources,
+    ) -> Result<Clocks, ClockError> {
+        let (state, _) = sysclk.run_from_fast_rc(FastRCFreq::_4_MHZ)?;
+
+        sysclk
+            .pmc_fspr
+            .write(|w| unsafe { w.bits(wakeup.wkup_active_high as u32) });
+        sysclk.pmc_fsmr.write(|w| {
+            unsafe { w.bits(wakeup.wkup_pins as u32) }
+                .rttal()
+                .bit(wakeup.rtt_alarm)
+                .rtcal()
+                .bit(wakeup.rtc_alarm)
+                .lpm()
+                .set_bit()
+        });
+
+        self.scb.clear_sleepdeep();
+        // A pending event would make WFE return at once, so set the event
+        // register and consume it before the WFE that enters Wait mode
+        asm::sev();
+        asm::wfe();
+        asm::wfe();
+
+        // Wait until the fast RC oscillator has restarted
+        // 0 = not stabilized, 1 = stabilized
+        sysclk.wait_until(
+            CLOCK_TIMEOUT_SLCK,
+            ClockError::OscillatorStartupTimeout,
+            |s| s.pmc_sr.read().moscrcs().bits(),
+        )?;
+
+        sysclk.pmc_fsmr.modify(|_, w| w.lpm().clear_bit());
+        sysclk.restore_clock_state(state)
+    }
+
+    /// Enter Backup mode, switching off the core's voltage regulator until
+    /// one of the `wakeup` sources triggers.  Only the backup domain (SUPC,
+    /// RTC, RTT and the general purpose backup registers) is kept powered,
+    /// and waking up resets the core.
+    pub fn backup(&mut self, sysclk: &mut SystemClocks, wakeup: WakeupSources) -> ! {
+        sysclk.supc.wuir.write(|w| unsafe {
+            w.bits(((wakeup.wkup_active_high as u32) << 16) | wakeup.wkup_pins as u32)
+        });
+        sysclk.supc.wumr.modify(|_, w| {
+            w.rtten()
+                .bit(wakeup.rtt_alarm)
+                .rtcen()
+                .bit(wakeup.rtc_alarm)
+        });
+
+        self.scb.set_sleepdeep();
+        sysclk
+            .supc
+            .cr
+            .write_with_zero(|w| w.key().passwd().vroff().set_bit());
+
+        // The core is powered off once the regulator stops
+        loop {
+            asm::wfe();
+        }
+    }
+}
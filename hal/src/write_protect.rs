@@ -0,0 +1,271 @@
+//! Protecting peripheral configurations against accidental writes.
+//!
+//! Most configuration registers of the PIO controllers, PMC, ADC, DACC,
+//! PWM, SPI, TC, HSMCI, USART, SSC and bus matrix can be write protected,
+//! so that firmware can lock its pin and clock configuration once
+//! initialized.  Writes to a protected register are ignored, and reported
+//! in the peripheral's write protect status register.  Each peripheral
+//! only accepts a change of protection along with its own key, which this
+//! module supplies.
+//!
+//! Drivers configuring a protected peripheral, such as `SystemClocks` for
+//! the PMC, have their writes ignored until protection is disabled again.
+use crate::target_device::{
+    ADC, DACC, HSMCI, MATRIX, PIOA, PIOB, PMC, PWM, SPI0, SSC, TC0, TC1, USART0, USART1, USART2,
+};
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+use crate::target_device::{PIOC, PIOD, TC2, USART3};
+
+#[cfg(feature = "sam3x8h")]
+use crate::target_device::{PIOE, PIOF, SPI1};
+
+/// A write attempted on a protected register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteProtectViolation {
+    /// Offset of the register, within the peripheral, of the last write
+    /// attempted while protected
+    pub offset: u16,
+}
+
+/// Peripherals whose configuration registers can be write protected.
+pub trait WriteProtect {
+    /// Ignore writes to the configuration registers.
+    fn enable_write_protection(&mut self);
+
+    /// Accept writes to the configuration registers.
+    fn disable_write_protection(&mut self);
+
+    /// Return whether writes to the configuration registers are ignored.
+    fn is_write_protected(&self) -> bool;
+
+    /// Return the last write attempted while protected, if any occurred
+    /// since the previous call.  Reading the status clears it.
+    fn write_protect_violation(&self) -> Option<WriteProtectViolation>;
+}
+
+// WPMR layout shared by all peripherals but the PWM controller
+fn wpmr_bits(key: u32, enable: bool) -> u32 {
+    (key << 8) | enable as u32
+}
+
+// WPSR layout shared by most peripherals: WPVS in bit 0, and the offset of
+// the offending register in WPVSRC, bits 8-23
+fn violation_from_wpsr(wpsr: u32) -> Option<WriteProtectViolation> {
+    if wpsr & 1 != 0 {
+        Some(WriteProtectViolation {
+            offset: (wpsr >> 8) as u16,
+        })
+    } else {
+        None
+    }
+}
+
+// The DACC only reports the register offset in bits 8-15
+fn violation_from_dacc_wpsr(wpsr: u32) -> Option<WriteProtectViolation> {
+    violation_from_wpsr(wpsr & 0xff_ff)
+}
+
+// The HSMCI has a 4-bit WP_VS: 1 for a write, 2 for a software reset, and
+// 3 for both
+fn violation_from_hsmci_wpsr(wpsr: u32) -> Option<WriteProtectViolation> {
+    if wpsr & 0xf != 0 {
+        Some(WriteProtectViolation {
+            offset: (wpsr >> 8) as u16,
+        })
+    } else {
+        None
+    }
+}
+
+// The PWM controller has WPVS in bit 7, and WPVSRC in bits 16-31
+fn violation_from_pwm_wpsr(wpsr: u32) -> Option<WriteProtectViolation> {
+    if wpsr & (1 << 7) != 0 {
+        Some(WriteProtectViolation {
+            offset: (wpsr >> 16) as u16,
+        })
+    } else {
+        None
+    }
+}
+
+macro_rules! write_protect {
+    ($($Periph:ident: $wpmr:ident, $wpsr:ident, $key:expr, $violation:ident;)+) => {
+        $(
+            impl WriteProtect for $Periph {
+                fn enable_write_protection(&mut self) {
+                    self.$wpmr
+                        .write_with_zero(|w| unsafe { w.bits(wpmr_bits($key, true)) });
+                }
+
+                fn disable_write_protection(&mut self) {
+                    self.$wpmr
+                        .write_with_zero(|w| unsafe { w.bits(wpmr_bits($key, false)) });
+                }
+
+                fn is_write_protected(&self) -> bool {
+                    self.$wpmr.read().bits() & 1 != 0
+                }
+
+                fn write_protect_violation(&self) -> Option<WriteProtectViolation> {
+                    $violation(self.$wpsr.read().bits())
+                }
+            }
+        )+
+    };
+}
+
+// Keys are the peripheral's name in ASCII
+const PIO_KEY: u32 = 0x50_49_4f; // "PIO"
+const PMC_KEY: u32 = 0x50_4d_43; // "PMC"
+const ADC_KEY: u32 = 0x41_44_43; // "ADC"
+const DACC_KEY: u32 = 0x44_41_43; // "DAC"
+const PWM_KEY: u32 = 0x50_57_4d; // "PWM"
+const SPI_KEY: u32 = 0x53_50_49; // "SPI"
+const TC_KEY: u32 = 0x54_49_4d; // "TIM"
+const HSMCI_KEY: u32 = 0x4d_43_49; // "MCI"
+const USART_KEY: u32 = 0x55_53_41; // "USA"
+const SSC_KEY: u32 = 0x53_53_43; // "SSC"
+const MATRIX_KEY: u32 = 0x4d_41_54; // "MAT"
+
+write_protect! {
+    PIOA: wpmr, wpsr, PIO_KEY, violation_from_wpsr;
+    PIOB: wpmr, wpsr, PIO_KEY, violation_from_wpsr;
+    PMC: pmc_wpmr, pmc_wpsr, PMC_KEY, violation_from_wpsr;
+    ADC: wpmr, wpsr, ADC_KEY, violation_from_wpsr;
+    DACC: wpmr, wpsr, DACC_KEY, violation_from_dacc_wpsr;
+    SPI0: wpmr, wpsr, SPI_KEY, violation_from_wpsr;
+    HSMCI: wpmr, wpsr, HSMCI_KEY, violation_from_hsmci_wpsr;
+    USART0: wpmr, wpsr, USART_KEY, violation_from_wpsr;
+    USART1: wpmr, wpsr, USART_KEY, violation_from_wpsr;
+    USART2: wpmr, wpsr, USART_KEY, violation_from_wpsr;
+    SSC: wpmr, wpsr, SSC_KEY, violation_from_wpsr;
+    MATRIX: matrix_wpmr, matrix_wpsr, MATRIX_KEY, violation_from_wpsr;
+}
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+write_protect! {
+    PIOC: wpmr, wpsr, PIO_KEY, violation_from_wpsr;
+    PIOD: wpmr, wpsr, PIO_KEY, violation_from_wpsr;
+    USART3: wpmr, wpsr, USART_KEY, violation_from_wpsr;
+}
+
+#[cfg(feature = "sam3x8h")]
+write_protect! {
+    PIOE: wpmr, wpsr, PIO_KEY, violation_from_wpsr;
+    PIOF: wpmr, wpsr, PIO_KEY, violation_from_wpsr;
+    SPI1: wpmr, wpsr, SPI_KEY, violation_from_wpsr;
+}
+
+// Timer counter blocks have no write protect status register
+macro_rules! tc_write_protect {
+    ($($Periph:ident,)+) => {
+        $(
+            impl WriteProtect for $Periph {
+                fn enable_write_protection(&mut self) {
+                    self.wpmr
+                        .write_with_zero(|w| unsafe { w.bits(wpmr_bits(TC_KEY, true)) });
+                }
+
+                fn disable_write_protection(&mut self) {
+                    self.wpmr
+                        .write_with_zero(|w| unsafe { w.bits(wpmr_bits(TC_KEY, false)) });
+                }
+
+                fn is_write_protected(&self) -> bool {
+                    self.wpmr.read().bits() & 1 != 0
+                }
+
+                /// Timer counters don't report violations; always `None`.
+                fn write_protect_violation(&self) -> Option<WriteProtectViolation> {
+                    None
+                }
+            }
+        )+
+    };
+}
+
+tc_write_protect!(TC0, TC1,);
+
+#[cfg(any(feature = "sam3_e", feature = "sam3x8h"))]
+tc_write_protect!(TC2,);
+
+// WPCR commands, applied to the register groups selected in bits 2-7
+const PWM_WPCMD_DISABLE_SW: u32 = 0;
+const PWM_WPCMD_ENABLE_SW: u32 = 1;
+const PWM_WPCMD_ENABLE_HW: u32 = 2;
+const PWM_WPRG_ALL: u32 = 0x3f << 2;
+
+fn pwm_write_protect_command(pwm: &mut PWM, command: u32) {
+    pwm.wpcr
+        .write_with_zero(|w| unsafe { w.bits((PWM_KEY << 8) | PWM_WPRG_ALL | command) });
+}
+
+/// Ignore writes to the PWM controller's configuration registers until the
+/// next reset; unlike `enable_write_protection()`, this can't be undone.
+pub fn lock_pwm_configuration(pwm: &mut PWM) {
+    pwm_write_protect_command(pwm, PWM_WPCMD_ENABLE_HW);
+}
+
+/// The PWM controller protects its six register groups separately; these
+/// methods apply to all of them.
+impl WriteProtect for PWM {
+    fn enable_write_protection(&mut self) {
+        pwm_write_protect_command(self, PWM_WPCMD_ENABLE_SW);
+    }
+
+    /// Registers locked with `lock_pwm_configuration()` stay protected.
+    fn disable_write_protection(&mut self) {
+        pwm_write_protect_command(self, PWM_WPCMD_DISABLE_SW);
+    }
+
+    /// The status is only available from WPSR, so this also clears any
+    /// violation not yet reported.
+    fn is_write_protected(&self) -> bool {
+        // WPSWS0-5 and WPHWS0-5
+        let wpsr = self.wpsr.read().bits();
+        wpsr & 0x3f != 0 || wpsr & (0x3f << 8) != 0
+    }
+
+    fn write_protect_violation(&self) -> Option<WriteProtectViolation> {
+        violation_from_pwm_wpsr(self.wpsr.read().bits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::write_protect::*;
+
+    #[test]
+    fn wpmr_carries_key_and_enable() {
+        assert_eq!(wpmr_bits(PIO_KEY, true), 0x50_49_4f_01);
+        assert_eq!(wpmr_bits(PMC_KEY, false), 0x50_4d_43_00);
+    }
+
+    #[test]
+    fn violations_decode_per_layout() {
+        assert_eq!(violation_from_wpsr(0x0000_4400), None);
+        assert_eq!(
+            violation_from_wpsr(0x0000_4401),
+            Some(WriteProtectViolation { offset: 0x44 })
+        );
+        assert_eq!(
+            violation_from_dacc_wpsr(0xffff_0401),
+            Some(WriteProtectViolation { offset: 0x04 })
+        );
+        assert_eq!(violation_from_hsmci_wpsr(0x0000_5000), None);
+        assert_eq!(
+            violation_from_hsmci_wpsr(0x0000_5002),
+            Some(WriteProtectViolation { offset: 0x50 })
+        );
+        assert_eq!(
+            violation_from_hsmci_wpsr(0x0000_0403),
+            Some(WriteProtectViolation { offset: 0x04 })
+        );
+        assert_eq!(violation_from_pwm_wpsr(0x0200_003f), None);
+        assert_eq!(
+            violation_from_pwm_wpsr(0x0200_0080),
+            Some(WriteProtectViolation { offset: 0x200 })
+        );
+    }
+}